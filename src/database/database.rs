@@ -1,49 +1,106 @@
-use crate::database::{
-    graphql::{
-        graphql::{graphiql, graphql_handler},
-        mutation::MutationRoot,
-        query::QueryRoot
+use crate::{
+    database::{
+        graphql::{
+            graphql::{graphiql, graphql_handler, graphql_subscription_handler, OperationMetrics},
+            mutation::MutationRoot,
+            query::QueryRoot,
+            subscription::{DataFeed, SubscriptionRoot}
+        },
+        rest::rest::hello_rest,
+        structures::RetryPolicy
     },
-    rest::rest::hello_rest
+    health::{
+        health::{healthz, readyz, status},
+        structures::HealthState
+    },
+    metrics::{
+        metrics::metrics_handler,
+        structures::Metrics
+    },
+    supervisor::structures::Health,
+    utils::{
+        shutdown::SHUTDOWN_TIMEOUT,
+        telemetry::http_trace_layer
+    }
 };
 
-use async_graphql::{ 
-    EmptySubscription, 
-    Schema
-};
+use async_graphql::{extensions::Tracing, Schema};
 use axum::{
+    Extension,
     routing::get, Router
 };
+use chrono::{DateTime, Utc};
 use common::utils::log::{
     LogFile, 
     LogLevel
 };
 use sqlx::{
+    FromRow,
     PgPool,
     postgres::{
+        PgConnection,
         PgPoolOptions, 
+        PgRow,
         Postgres, 
     },  
     QueryBuilder
 };
-use std::sync::Arc;
+use std::{
+    fmt,
+    io,
+    sync::{
+        Arc,
+        atomic::{AtomicI64, Ordering}
+    },
+    time::Instant
+};
 use tokio::{
     net::TcpListener,
-    time::{Duration, sleep},
+    time::{sleep, timeout},
 };
+use tokio_util::sync::CancellationToken;
+use tracing::instrument;
+
+// Number of rows kept for slow subscribers before they start lagging
+const FEED_CAPACITY: usize = 1024;
+
+// Time of the last successful insert in milliseconds, zero until the first one
+// Shared by the GraphQL mutations and the websocket persistence
+static LAST_INSERT: AtomicI64 = AtomicI64::new(0);
 
-pub async fn launch_database(adress: String, database_url: String) -> Result<(), Box<dyn std::error::Error>> {
+pub async fn launch_database(adress: String, database_url: String, subsystems: Health, shutdown: CancellationToken) -> Result<(), Box<dyn std::error::Error>> {
     let pool = PgPoolOptions::new()
         .max_connections(5)
         .connect(&database_url).await?;
 
-    let schema = Schema::build(QueryRoot, MutationRoot, EmptySubscription)
-        .data(pool)
+    // One span per GraphQL operation and resolver, and the latency of every root field
+    let schema = Schema::build(QueryRoot, MutationRoot, SubscriptionRoot)
+        .extension(Tracing)
+        .extension(OperationMetrics)
+        .data(pool.clone())
+        .data(DataFeed::new(FEED_CAPACITY))
+        .data(RetryPolicy::from_env())
+        .data(shutdown.clone())
         .finish();
 
+    Metrics::global().watch_pool("database", pool.clone());
+
+    let health = HealthState {
+        pool: pool.clone(),
+        subsystems,
+        feed: None,
+    };
+
     let app = Router::new()
         .route("/data", get(graphiql).post(graphql_handler)) // GraphQL interface
+        .route("/data/ws", get(graphql_subscription_handler)) // GraphQL subscriptions (graphql-ws)
         .route("/api", get(hello_rest).post(hello_rest)) // REST endpoint (GET/POST)
+        .route("/healthz", get(healthz)) // Liveness
+        .route("/readyz", get(readyz)) // Readiness
+        .route("/status", get(status)) // Detailed status
+        .route("/metrics", get(metrics_handler)) // Prometheus metrics
+        .layer(Extension(health))
+        .layer(http_trace_layer())
         .with_state(Arc::new(schema));
 
     let listener = TcpListener::bind(&adress).await;
@@ -55,35 +112,239 @@ pub async fn launch_database(adress: String, database_url: String) -> Result<(),
 
     let listener = listener.unwrap();
 
-    if let Err(e) = axum::serve(listener, app).into_future().await {
-        LogFile::add_log(LogLevel::Error, &format!("Failed to start server: {}", e)).ok();
+    // Stop accepting connections on shutdown and let the running requests finish
+    // So the inserts of a post are not cut halfway
+    let server = axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown.clone().cancelled_owned())
+        .into_future();
 
-        return Err(Box::new(e));
+    let drain_deadline = async {
+        shutdown.cancelled().await;
+        sleep(SHUTDOWN_TIMEOUT).await;
+    };
+
+    tokio::select! {
+        result = server => if let Err(e) = result {
+            LogFile::add_log(LogLevel::Error, &format!("Failed to start server: {}", e)).ok();
+            pool.close().await;
+
+            return Err(Box::new(e));
+        },
+        _ = drain_deadline => {
+            LogFile::add_log(LogLevel::Error, "Database server didn't drain in time, closing anyway").ok();
+        },
     }
 
-    LogFile::add_log(LogLevel::Info, &format!("Database server running on {}", adress)).ok();
+    pool.close().await;
+
+    LogFile::add_log(LogLevel::Info, &format!("Database server on {} stopped", adress)).ok();
 
     Ok(())
 }
 
-pub async fn perform_insert<'a, F>(pool: Arc<PgPool>, build_query_builder: F) -> Result<u64, sqlx::Error> 
-where F: Fn() -> QueryBuilder<'a, Postgres> {
-    let mut last_err = None;
+// Where an insert runs
+pub enum InsertTarget<'c> {
+    // Retried following the policy, each attempt on a connection of the pool
+    Pool(Arc<PgPool>, RetryPolicy),
+    // Run once, a failed statement aborts the whole transaction anyway
+    // The rows are recorded by the caller once committed
+    Transaction(&'c mut PgConnection),
+}
+
+impl InsertTarget<'_> {
+    // Same target for one more statement, such as the next chunk of a batch
+    pub fn reborrow(&mut self) -> InsertTarget<'_> {
+        match self {
+            InsertTarget::Pool(pool, policy) => InsertTarget::Pool(Arc::clone(pool), *policy),
+            InsertTarget::Transaction(connection) => InsertTarget::Transaction(connection),
+        }
+    }
+}
 
-    for attempt in 0..5 {
-        let mut query_builder = build_query_builder();
-        let query = query_builder.build();
+// Rows written by an insert and the retries it took
+pub struct Inserted<T> {
+    pub rows: Vec<T>,
+    pub retries: u32,
+}
+
+// Last error of an insert and the retries made before giving up
+#[derive(Debug)]
+pub struct InsertError {
+    pub error: sqlx::Error,
+    pub retries: u32,
+}
+
+impl fmt::Display for InsertError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (after {} retries)", self.error, self.retries)
+    }
+}
+
+// Run the insert and return the rows written
+// So the caller can forward them to the subscribers
+// On the pool the deadline runs from started, which the statements of one batch share
+#[instrument(name = "db.insert", skip_all, fields(table = table), err)]
+pub async fn perform_insert<'a, T, F>(target: InsertTarget<'_>, table: &str, started: Instant, build_query_builder: F) -> Result<Inserted<T>, InsertError> 
+where T: for<'r> FromRow<'r, PgRow> + Send + Unpin, F: Fn() -> QueryBuilder<'a, Postgres> {
+    let metrics = Metrics::global();
 
-        match query.execute(pool.as_ref()).await {
-            Ok(result) => return Ok(result.rows_affected()),
-            Err(e) => {
-                query_builder.reset();
-                last_err = Some(e);
+    let (pool, policy) = match target {
+        InsertTarget::Pool(pool, policy) => (pool, policy),
+        InsertTarget::Transaction(connection) => {
+            let mut query_builder = build_query_builder();
+            let res = query_builder.build_query_as::<T>().fetch_all(connection).await;
 
-                sleep(Duration::from_millis(100 * attempt)).await;
+            if res.is_err() {
+                metrics.insert_failures.with_label_values(&[table]).inc();
             }
+
+            return res
+                .map(|rows| Inserted { rows, retries: 0 })
+                .map_err(|error| InsertError { error, retries: 0 });
+        }
+    };
+
+    let mut retries = 0;
+
+    loop {
+        let mut query_builder = build_query_builder();
+        let query = query_builder.build_query_as::<T>();
+
+        // An attempt stuck on a dead connection is cut at the deadline
+        let error = match timeout(policy.remaining(started.elapsed()), query.fetch_all(pool.as_ref())).await {
+            Ok(Ok(rows)) => {
+                record_insert(table, rows.len());
+
+                return Ok(Inserted { rows, retries });
+            },
+            Ok(Err(e)) => e,
+            Err(_) => deadline_error(),
+        };
+
+        let attempt = retries + 1;
+        let backoff = policy.backoff(retries);
+
+        if let Some(reason) = policy.give_up(is_retriable(&error), retries, started.elapsed(), backoff) {
+            LogFile::add_log(LogLevel::Error, &format!("Insert into {} failed on attempt {}, {}: {}", table, attempt, reason, error)).ok();
+            metrics.insert_failures.with_label_values(&[table]).inc();
+
+            return Err(InsertError { error, retries });
+        }
+
+        LogFile::add_log(LogLevel::Error, &format!("Insert into {} failed on attempt {}, retrying in {:?}: {}", table, attempt, backoff, error)).ok();
+
+        sleep(backoff).await;
+
+        retries += 1;
+        metrics.insert_retries.with_label_values(&[table]).inc();
+    }
+}
+
+// Only errors that may pass on a second try are retried
+// A constraint or data error fails the same way every time
+pub fn is_retriable(error: &sqlx::Error) -> bool {
+    match error {
+        sqlx::Error::Database(e) => match e.code() {
+            // serialization_failure, deadlock_detected
+            Some(code) if code == "40001" || code == "40P01" => true,
+            // Connection exceptions and insufficient resources
+            Some(code) if code.starts_with("08") || code.starts_with("53") => true,
+            // admin_shutdown, crash_shutdown, cannot_connect_now
+            Some(code) if code == "57P01" || code == "57P02" || code == "57P03" => true,
+            _ => false,
+        },
+        // The connection was lost or the pool had none to give
+        sqlx::Error::Io(_) | sqlx::Error::PoolTimedOut | sqlx::Error::WorkerCrashed => true,
+        _ => false,
+    }
+}
+
+// An attempt cut at the deadline, reported like a lost connection
+pub fn deadline_error() -> sqlx::Error {
+    sqlx::Error::Io(io::Error::new(io::ErrorKind::TimedOut, "deadline reached"))
+}
+
+// Called once the rows of a table are committed
+pub fn record_insert(table: &str, rows: usize) {
+    LAST_INSERT.store(Utc::now().timestamp_millis(), Ordering::Relaxed);
+    Metrics::global().rows_inserted.with_label_values(&[table]).inc_by(rows as u64);
+}
+
+pub fn last_insert() -> Option<DateTime<Utc>> {
+    match LAST_INSERT.load(Ordering::Relaxed) {
+        0 => None,
+        millis => DateTime::from_timestamp_millis(millis),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{deadline_error, is_retriable};
+
+    use sqlx::error::{DatabaseError, ErrorKind};
+    use std::{borrow::Cow, error::Error, fmt};
+
+    // A Postgres error carrying only its SQLSTATE
+    #[derive(Debug)]
+    struct SqlState(&'static str);
+
+    impl fmt::Display for SqlState {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "SQLSTATE {}", self.0)
+        }
+    }
+
+    impl Error for SqlState {}
+
+    impl DatabaseError for SqlState {
+        fn message(&self) -> &str {
+            self.0
+        }
+
+        fn code(&self) -> Option<Cow<'_, str>> {
+            Some(Cow::Borrowed(self.0))
+        }
+
+        fn as_error(&self) -> &(dyn Error + Send + Sync + 'static) {
+            self
+        }
+
+        fn as_error_mut(&mut self) -> &mut (dyn Error + Send + Sync + 'static) {
+            self
+        }
+
+        fn into_error(self: Box<Self>) -> Box<dyn Error + Send + Sync + 'static> {
+            self
+        }
+
+        fn kind(&self) -> ErrorKind {
+            ErrorKind::Other
+        }
+    }
+
+    fn database_error(code: &'static str) -> sqlx::Error {
+        sqlx::Error::Database(Box::new(SqlState(code)))
+    }
+
+    #[test]
+    fn retries_transient_errors() {
+        for code in ["40001", "40P01", "08006", "53300", "57P01", "57P02", "57P03"] {
+            assert!(is_retriable(&database_error(code)), "{} should be retried", code);
         }
+
+        assert!(is_retriable(&sqlx::Error::PoolTimedOut));
+        assert!(is_retriable(&sqlx::Error::WorkerCrashed));
+        assert!(is_retriable(&deadline_error()));
     }
 
-    Err(last_err.unwrap_or_else(|| sqlx::Error::Protocol("Unknown error".into())))
+    #[test]
+    fn fails_fast_on_data_errors() {
+        // unique_violation, foreign_key_violation, check_violation, invalid_text_representation, query_canceled
+        for code in ["23505", "23503", "23514", "22P02", "57014"] {
+            assert!(!is_retriable(&database_error(code)), "{} should not be retried", code);
+        }
+
+        assert!(!is_retriable(&sqlx::Error::RowNotFound));
+        assert!(!is_retriable(&sqlx::Error::PoolClosed));
+    }
 }
\ No newline at end of file
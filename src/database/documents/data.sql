@@ -32,10 +32,11 @@ CREATE TABLE sessions (
     open DOUBLE PRECISION NOT NULL,
     close DOUBLE PRECISION NOT NULL,
     volume DOUBLE PRECISION NOT NULL,
-    UNIQUE(label, start_time),
+    UNIQUE(symbol, label, start_time),
     FOREIGN KEY (symbol) REFERENCES symbols(symbol)
 );
 CREATE INDEX ON sessions (label, start_time DESC);
+CREATE INDEX ON sessions (symbol, start_time DESC);
 
 CREATE TABLE two_d_structures (
     id SERIAL PRIMARY KEY,
@@ -46,10 +47,11 @@ CREATE TABLE two_d_structures (
     high DOUBLE PRECISION NOT NULL,
     low DOUBLE PRECISION NOT NULL,
     direction TEXT NOT NULL,
-    UNIQUE (structure, timerange, timestamp),
+    UNIQUE (symbol, structure, timerange, timestamp),
     FOREIGN KEY (symbol) REFERENCES symbols(symbol)
 );
 CREATE INDEX ON two_d_structures (structure, timerange, timestamp DESC);
+CREATE INDEX ON two_d_structures (symbol, timerange, timestamp DESC);
 
 CREATE TABLE one_d_structures (
     id SERIAL PRIMARY KEY,
@@ -59,10 +61,11 @@ CREATE TABLE one_d_structures (
     timestamp TIMESTAMPTZ NOT NULL,
     price DOUBLE PRECISION NOT NULL,
     direction TEXT NOT NULL,
-    UNIQUE (structure, timerange, timestamp),
+    UNIQUE (symbol, structure, timerange, timestamp),
     FOREIGN KEY (symbol) REFERENCES symbols(symbol)
 );
 CREATE INDEX ON one_d_structures (structure, timerange, timestamp DESC);
+CREATE INDEX ON one_d_structures (symbol, timerange, timestamp DESC);
 
 CREATE TABLE trends (
     id SERIAL PRIMARY KEY,
@@ -0,0 +1,24 @@
+-- SQL script to upgrade a database created with an older data.sql
+-- Every statement can be run again safely, run it in one transaction with psql -1
+
+-- The upserts conflict on the natural keys, which include the symbol
+-- The constraints are recreated under the names data.sql gives them
+ALTER TABLE sessions
+    DROP CONSTRAINT IF EXISTS sessions_label_start_time_key,
+    DROP CONSTRAINT IF EXISTS sessions_symbol_label_start_time_key,
+    ADD CONSTRAINT sessions_symbol_label_start_time_key UNIQUE (symbol, label, start_time);
+
+ALTER TABLE two_d_structures
+    DROP CONSTRAINT IF EXISTS two_d_structures_structure_timerange_timestamp_key,
+    DROP CONSTRAINT IF EXISTS two_d_structures_symbol_structure_timerange_timestamp_key,
+    ADD CONSTRAINT two_d_structures_symbol_structure_timerange_timestamp_key UNIQUE (symbol, structure, timerange, timestamp);
+
+ALTER TABLE one_d_structures
+    DROP CONSTRAINT IF EXISTS one_d_structures_structure_timerange_timestamp_key,
+    DROP CONSTRAINT IF EXISTS one_d_structures_symbol_structure_timerange_timestamp_key,
+    ADD CONSTRAINT one_d_structures_symbol_structure_timerange_timestamp_key UNIQUE (symbol, structure, timerange, timestamp);
+
+-- The entity queries select by symbol and timerange, newest first
+CREATE INDEX IF NOT EXISTS sessions_symbol_start_time_idx ON sessions (symbol, start_time DESC);
+CREATE INDEX IF NOT EXISTS two_d_structures_symbol_timerange_timestamp_idx ON two_d_structures (symbol, timerange, timestamp DESC);
+CREATE INDEX IF NOT EXISTS one_d_structures_symbol_timerange_timestamp_idx ON one_d_structures (symbol, timerange, timestamp DESC);
\ No newline at end of file
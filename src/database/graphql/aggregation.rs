@@ -0,0 +1,246 @@
+use crate::{database::graphql::filters::{Direction, TimeBounds}, Candle, SESSIONS, TIMERANGES};
+
+use chrono::NaiveTime;
+use sqlx::{PgPool, query_as};
+use tracing::instrument;
+
+const DAY_SECONDS: i64 = 86_400;
+
+// Largest bucket and number of aggregated candles a query can ask for
+pub const MAX_BUCKET_SECONDS: i64 = 366 * DAY_SECONDS;
+pub const MAX_AGGREGATED_LIMIT: i64 = 10_000;
+
+// Width of the aggregated candles
+pub struct Bucket {
+    // Timerange given to the aggregated candles
+    pub label: String,
+    pub seconds: i64,
+}
+
+// Buckets start at the session open and only cover its hours
+pub struct SessionWindow {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+// Duration of a timerange from TIMERANGES, in seconds
+pub fn timerange_seconds(label: &str) -> Option<i64> {
+    TIMERANGES.iter()
+        .find(|timerange| timerange.label == label)
+        .map(|timerange| (timerange.duration_ms / 1000) as i64)
+}
+
+// The target is either a timerange of TIMERANGES or an arbitrary number of seconds
+// It must be a multiple of the source, so no source candle overlaps two buckets
+// The limit is checked with it, the lookback is computed from both
+pub fn resolve_bucket(source: &str, timerange: Option<&str>, bucket_seconds: Option<i64>, limit: i64) -> Result<Bucket, String> {
+    if !(0..=MAX_AGGREGATED_LIMIT).contains(&limit) {
+        return Err(format!("limit must be between 0 and {}", MAX_AGGREGATED_LIMIT));
+    }
+
+    let source_seconds = timerange_seconds(source)
+        .ok_or(format!("Unknown source timerange {}", source))?;
+
+    let bucket = match (timerange, bucket_seconds) {
+        (Some(label), None) => Bucket {
+            label: label.to_string(),
+            seconds: timerange_seconds(label).ok_or(format!("Unknown timerange {}", label))?,
+        },
+        (None, Some(seconds)) => Bucket {
+            label: format!("{}s", seconds),
+            seconds,
+        },
+        _ => return Err("Give either a timerange or a bucket size".to_string()),
+    };
+
+    if bucket.seconds > MAX_BUCKET_SECONDS {
+        return Err(format!("bucketSeconds must be at most {}", MAX_BUCKET_SECONDS));
+    }
+
+    if bucket.seconds <= source_seconds || bucket.seconds % source_seconds != 0 {
+        return Err(format!("{} is not a multiple of {}", bucket.label, source));
+    }
+
+    Ok(bucket)
+}
+
+pub fn resolve_session(label: &str) -> Result<SessionWindow, String> {
+    let session = SESSIONS.iter()
+        .find(|session| session.label == label)
+        .ok_or(format!("Unknown session {}", label))?;
+
+    let parse = |time: &str| NaiveTime::parse_from_str(time, "%H:%M")
+        .map_err(|e| format!("Invalid hours for session {}: {}", label, e));
+
+    Ok(SessionWindow {
+        start: parse(session.start)?,
+        end: parse(session.end)?,
+    })
+}
+
+// Without a minTimestamp only the source candles the limit can need are read
+// Two buckets more than the limit, for the partial ones cut at both ends
+// With a session a day only holds the buckets of its hours, so whole days are read
+// The arguments are checked by resolve_bucket, the arithmetic saturates anyway
+pub fn lookback_seconds(bucket: &Bucket, session: Option<&SessionWindow>, limit: i64) -> i64 {
+    let buckets = limit.max(0).saturating_add(2);
+    let seconds = bucket.seconds.max(1);
+
+    match session {
+        None => buckets.saturating_mul(seconds),
+        Some(session) => {
+            let length = (session.end - session.start).num_seconds().rem_euclid(DAY_SECONDS);
+            let length = if length == 0 { DAY_SECONDS } else { length };
+            let per_day = (length - 1) / seconds + 1;
+
+            ((buckets - 1) / per_day + 1).saturating_add(1).saturating_mul(DAY_SECONDS)
+        }
+    }
+}
+
+// Aggregate the stored candles of a timerange into larger ones, newest first
+// Open is the first open of the bucket, close the last close, high the max, low the min and volume the sum
+// The direction follows close against open, a flat bucket keeps the direction of its last candle
+// A bucket cut by the bounds or still being formed is partial and left out unless asked for
+#[allow(clippy::too_many_arguments)]
+#[instrument(name = "db.aggregate", skip_all, fields(table = "candles", symbol = symbol, bucket = bucket.label.as_str()), err)]
+pub async fn aggregate_candles(pool: &PgPool, symbol: &str, source: &str, bucket: &Bucket, session: Option<&SessionWindow>, bounds: &TimeBounds, limit: i64, include_partial: bool) -> Result<Vec<Candle>, sqlx::Error> {
+    let source_seconds = timerange_seconds(source).unwrap_or_default();
+
+    // The bounds apply to the source candles, like for the candles query
+    // A bucket is complete when it starts after the lower bound and ends before the last candle read does
+    let sql = format!(r#"
+        WITH latest AS (
+            SELECT MAX(timestamp) AS timestamp
+            FROM candles
+            WHERE symbol = $1
+                AND timerange = $2
+                AND ($5::TIMESTAMPTZ IS NULL OR timestamp {max} $5)
+        ),
+        window_bounds AS (
+            SELECT COALESCE($4::TIMESTAMPTZ, timestamp - $10::DOUBLE PRECISION * INTERVAL '1 second') AS lower,
+                timestamp + $11::DOUBLE PRECISION * INTERVAL '1 second' AS upper
+            FROM latest
+        ),
+        source AS (
+            -- Buckets are aligned on the open of the session the candle belongs to, or on the epoch
+            SELECT date_bin($3::DOUBLE PRECISION * INTERVAL '1 second', timestamp, CASE
+                    WHEN $6::TIME IS NULL THEN TIMESTAMPTZ '1970-01-01 00:00:00+00'
+                    WHEN (timestamp AT TIME ZONE 'UTC')::TIME >= $6 THEN (date_trunc('day', timestamp AT TIME ZONE 'UTC') + $6) AT TIME ZONE 'UTC'
+                    ELSE (date_trunc('day', timestamp AT TIME ZONE 'UTC') - INTERVAL '1 day' + $6) AT TIME ZONE 'UTC'
+                END) AS bucket, timestamp, open, high, low, close, volume, direction
+            FROM candles, window_bounds
+            WHERE symbol = $1
+                AND timerange = $2
+                AND timestamp {min} window_bounds.lower
+                AND ($5::TIMESTAMPTZ IS NULL OR timestamp {max} $5)
+                AND ($6::TIME IS NULL OR CASE
+                    WHEN $6 < $7 THEN (timestamp AT TIME ZONE 'UTC')::TIME >= $6 AND (timestamp AT TIME ZONE 'UTC')::TIME < $7
+                    ELSE (timestamp AT TIME ZONE 'UTC')::TIME >= $6 OR (timestamp AT TIME ZONE 'UTC')::TIME < $7
+                END)
+        ),
+        buckets AS (
+            SELECT bucket,
+                (array_agg(open ORDER BY timestamp ASC))[1] AS open,
+                MAX(high) AS high,
+                MIN(low) AS low,
+                (array_agg(close ORDER BY timestamp DESC))[1] AS close,
+                SUM(volume) AS volume,
+                (array_agg(direction ORDER BY timestamp DESC))[1] AS latest
+            FROM source
+            GROUP BY bucket
+        )
+        SELECT $1::TEXT AS symbol, $8::TEXT AS timerange, bucket AS timestamp, open, high, low, close, volume,
+            CASE
+                WHEN close > open THEN $13
+                WHEN close < open THEN $14
+                ELSE latest
+            END AS direction
+        FROM buckets, window_bounds
+        WHERE $12
+            OR (bucket {min} window_bounds.lower AND bucket + $3::DOUBLE PRECISION * INTERVAL '1 second' <= window_bounds.upper)
+        ORDER BY bucket DESC
+        LIMIT $9
+    "#, min = bounds.min_operator(), max = bounds.max_operator());
+
+    query_as::<_, Candle>(&sql)
+    .bind(symbol)
+    .bind(source)
+    .bind(bucket.seconds)
+    .bind(bounds.min)
+    .bind(bounds.max)
+    .bind(session.map(|session| session.start))
+    .bind(session.map(|session| session.end))
+    .bind(&bucket.label)
+    .bind(limit)
+    .bind(lookback_seconds(bucket, session, limit))
+    .bind(source_seconds)
+    .bind(include_partial)
+    .bind(Direction::Bullish.as_str())
+    .bind(Direction::Bearish.as_str())
+    .fetch_all(pool)
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{lookback_seconds, resolve_bucket, timerange_seconds, Bucket, SessionWindow, DAY_SECONDS, MAX_AGGREGATED_LIMIT, MAX_BUCKET_SECONDS};
+    use crate::TIMERANGES;
+
+    use chrono::NaiveTime;
+
+    fn bucket(seconds: i64) -> Bucket {
+        Bucket {
+            label: format!("{}s", seconds),
+            seconds,
+        }
+    }
+
+    fn session(start: u32, end: u32) -> SessionWindow {
+        SessionWindow {
+            start: NaiveTime::from_hms_opt(start, 0, 0).unwrap(),
+            end: NaiveTime::from_hms_opt(end, 0, 0).unwrap(),
+        }
+    }
+
+    #[test]
+    fn resolves_multiples_of_the_source() {
+        let source = TIMERANGES[0].label;
+        let seconds = timerange_seconds(source).unwrap();
+
+        assert_eq!(resolve_bucket(source, None, Some(seconds * 3), 10).unwrap().seconds, seconds * 3);
+
+        assert!(resolve_bucket(source, None, Some(seconds), 10).is_err());
+        assert!(resolve_bucket(source, None, Some(seconds * 3 + 1), 10).is_err());
+        assert!(resolve_bucket(source, None, None, 10).is_err());
+    }
+
+    #[test]
+    fn rejects_out_of_range_arguments() {
+        let source = TIMERANGES[0].label;
+        let seconds = timerange_seconds(source).unwrap();
+
+        assert!(resolve_bucket(source, None, Some(i64::MAX - i64::MAX % seconds), 10).is_err());
+        assert!(resolve_bucket(source, None, Some(MAX_BUCKET_SECONDS + seconds), 10).is_err());
+        assert!(resolve_bucket(source, None, Some(seconds * 3), -1).is_err());
+        assert!(resolve_bucket(source, None, Some(seconds * 3), MAX_AGGREGATED_LIMIT + 1).is_err());
+        assert!(resolve_bucket(source, None, Some(seconds * 3), i64::MAX).is_err());
+    }
+
+    #[test]
+    fn looks_back_over_the_limit_and_the_partial_buckets() {
+        assert_eq!(lookback_seconds(&bucket(300), None, 10), 12 * 300);
+
+        // Eight hours of 1h buckets a day, 12 buckets need two days and one more for the cut
+        assert_eq!(lookback_seconds(&bucket(3600), Some(&session(8, 16)), 10), 3 * DAY_SECONDS);
+
+        // A session wrapping midnight
+        assert_eq!(lookback_seconds(&bucket(3600), Some(&session(22, 6)), 6), 2 * DAY_SECONDS);
+    }
+
+    #[test]
+    fn lookback_saturates() {
+        assert_eq!(lookback_seconds(&bucket(i64::MAX), None, i64::MAX), i64::MAX);
+        assert_eq!(lookback_seconds(&bucket(1), Some(&session(8, 9)), i64::MAX), i64::MAX);
+    }
+}
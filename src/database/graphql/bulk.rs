@@ -0,0 +1,179 @@
+use crate::database::{
+    database::record_insert,
+    graphql::{
+        upsert::{dedup_by_key, push_upsert, ConflictMode, EntityResult, UpsertTable, CANDLE_UPSERT},
+        validation::{log_rejected, validate_candle, validate_rows}
+    }
+};
+
+use common::{
+    entities::candle::CandleInput,
+    utils::log::{LogFile, LogLevel}
+};
+use sqlx::{
+    PgPool,
+    postgres::{PgConnection, Postgres},
+    query,
+    QueryBuilder
+};
+use std::{
+    fmt::Write,
+    time::Instant
+};
+
+// Size of the CSV sent in each COPY message
+const COPY_BUFFER: usize = 1 << 20;
+
+// Backfill through COPY, for batches of millions of rows
+// The whole backfill is one transaction, so a failed one leaves nothing behind
+// It isn't retried here, it can outlast the retry deadline
+// The client can send it again as is, the rows are matched on their natural key
+// The rows are history, they are not forwarded to the subscribers
+pub async fn backfill_candles(pool: &PgPool, candles: &[CandleInput], mode: ConflictMode) -> EntityResult {
+    let started = Instant::now();
+    let (valid, rejected) = validate_rows(candles, validate_candle);
+    let valid = dedup_by_key(&valid, |candle| (&candle.symbol, &candle.timerange, candle.timestamp));
+
+    log_rejected("candles", &rejected);
+
+    let res = async {
+        let mut transaction = pool.begin().await?;
+        let counts = copy_upsert(&mut transaction, &CANDLE_UPSERT, &valid, |candle, buffer| csv_candle(candle, buffer), mode).await?;
+        transaction.commit().await?;
+
+        Ok::<_, sqlx::Error>(counts)
+    }.await;
+
+    match res {
+        Ok((inserted, written)) => {
+            record_insert("candles", written);
+            LogFile::add_log(LogLevel::Info, &format!("Backfilled {} candles", written)).ok();
+
+            EntityResult {
+                received: candles.len(),
+                written,
+                inserted,
+                updated: written - inserted,
+                skipped: candles.len() - rejected.len() - written,
+                rejected,
+                retries: 0,
+                elapsed_ms: started.elapsed().as_millis() as u64,
+                error: None,
+            }
+        },
+        Err(e) => {
+            LogFile::add_log(LogLevel::Error, &format!("Failed to backfill candles: {}", e)).ok();
+
+            EntityResult {
+                received: candles.len(),
+                rejected,
+                elapsed_ms: started.elapsed().as_millis() as u64,
+                error: Some(e.to_string()),
+                ..EntityResult::default()
+            }
+        },
+    }
+}
+
+// COPY the rows into a temporary table, then upsert them with the same conflict clause as the inserts
+// COPY has no bind parameters, so the batch size has no limit
+// The temporary table is dropped on commit, so the connection must be in a transaction
+// Returns the inserted and the written counts
+pub async fn copy_upsert<R>(connection: &mut PgConnection, table: &UpsertTable, rows: &[R], csv_row: impl Fn(&R, &mut String), mode: ConflictMode) -> Result<(usize, usize), sqlx::Error> {
+    // csv_row writes the keys then the values, in the order of the table
+    let columns = table.keys.iter()
+        .chain(table.values)
+        .copied()
+        .collect::<Vec<_>>()
+        .join(", ");
+    let staging = format!("{}_staging", table.name);
+
+    query(&format!("CREATE TEMPORARY TABLE {} ON COMMIT DROP AS SELECT {} FROM {} WITH NO DATA", staging, columns, table.name))
+        .execute(&mut *connection)
+        .await?;
+
+    let mut copy = connection.copy_in_raw(&format!("COPY {} ({}) FROM STDIN WITH (FORMAT csv)", staging, columns)).await?;
+    let mut buffer = String::with_capacity(COPY_BUFFER);
+
+    for row in rows {
+        csv_row(row, &mut buffer);
+
+        if buffer.len() >= COPY_BUFFER {
+            copy.send(buffer.as_bytes()).await?;
+            buffer.clear();
+        }
+    }
+
+    if !buffer.is_empty() {
+        copy.send(buffer.as_bytes()).await?;
+    }
+
+    copy.finish().await?;
+
+    // Only the counts are read back, the rows can be millions
+    let mut query_builder: QueryBuilder<Postgres> = QueryBuilder::new(format!("WITH written AS (INSERT INTO {} ({}) SELECT {} FROM {}", table.name, columns, columns, staging));
+    push_upsert(&mut query_builder, table, mode, "1 AS written");
+    query_builder.push(") SELECT count(*) FILTER (WHERE inserted), count(*) FROM written");
+
+    let (inserted, written): (i64, i64) = query_builder.build_query_as().fetch_one(&mut *connection).await?;
+
+    Ok((inserted as usize, written as usize))
+}
+
+// Keys then values of CANDLE_UPSERT
+fn csv_candle(candle: &CandleInput, buffer: &mut String) {
+    csv_text(buffer, &candle.symbol);
+    buffer.push(',');
+    csv_text(buffer, &candle.timerange);
+    write!(buffer, ",{},{},{},{},{},{},", candle.timestamp.to_rfc3339(), candle.open, candle.high, candle.low, candle.close, candle.volume).ok();
+    csv_text(buffer, &candle.direction);
+    buffer.push('\n');
+}
+
+// Quoted so a comma or a quote in the text can't shift the columns
+fn csv_text(buffer: &mut String, text: &str) {
+    buffer.push('"');
+    buffer.push_str(&text.replace('"', "\"\""));
+    buffer.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{csv_candle, csv_text};
+
+    use chrono::{TimeZone, Utc};
+    use common::entities::candle::CandleInput;
+
+    #[test]
+    fn quotes_text_fields() {
+        let mut buffer = String::new();
+
+        csv_text(&mut buffer, "BTC");
+        buffer.push(',');
+        csv_text(&mut buffer, "a,\"b\"");
+        buffer.push(',');
+        csv_text(&mut buffer, "");
+
+        assert_eq!(buffer, "\"BTC\",\"a,\"\"b\"\"\",\"\"");
+    }
+
+    #[test]
+    fn writes_candles_in_the_upsert_column_order() {
+        let candle = CandleInput {
+            symbol: "BTC".to_string(),
+            timerange: "1m".to_string(),
+            timestamp: Utc.with_ymd_and_hms(2024, 1, 1, 8, 0, 0).unwrap(),
+            open: 1.0,
+            high: 2.5,
+            low: 0.5,
+            close: 2.0,
+            volume: 10.0,
+            direction: "Bullish".to_string(),
+        };
+
+        let mut buffer = String::new();
+        csv_candle(&candle, &mut buffer);
+
+        assert_eq!(buffer, "\"BTC\",\"1m\",2024-01-01T08:00:00+00:00,1,2.5,0.5,2,10,\"Bullish\"\n");
+    }
+}
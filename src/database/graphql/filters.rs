@@ -0,0 +1,227 @@
+use crate::TIMERANGES;
+
+use async_graphql::{
+    Enum,
+    indexmap::IndexMap,
+    InputType,
+    InputValueError,
+    InputValueResult,
+    Name,
+    registry::{Deprecation, MetaEnumValue, MetaType, MetaTypeId, Registry},
+    Value
+};
+use chrono::{DateTime, Utc};
+use sqlx::{PgPool, query_scalar};
+use std::borrow::Cow;
+
+// A timerange of TIMERANGES, exposed as a GraphQL enum
+// The values follow TIMERANGES, so a new timerange needs no change here
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TimerangeLabel(&'static str);
+
+impl TimerangeLabel {
+    pub fn as_str(&self) -> &'static str {
+        self.0
+    }
+
+    // GraphQL names can't start with a digit, so 1m becomes T_1m
+    fn graphql_name(label: &str) -> String {
+        format!("T_{}", label)
+    }
+}
+
+impl InputType for TimerangeLabel {
+    type RawValueType = Self;
+
+    fn type_name() -> Cow<'static, str> {
+        Cow::Borrowed("Timerange")
+    }
+
+    fn create_type_info(registry: &mut Registry) -> String {
+        registry.create_input_type::<Self, _>(MetaTypeId::Enum, |_| MetaType::Enum {
+            name: Self::type_name().to_string(),
+            description: Some("Timeranges the candles and the structures are stored in".to_string()),
+            enum_values: TIMERANGES.iter()
+                .map(|timerange| {
+                    let name = Self::graphql_name(timerange.label);
+
+                    (name.clone(), MetaEnumValue {
+                        name,
+                        description: None,
+                        deprecation: Deprecation::NoDeprecated,
+                        visible: None,
+                        inaccessible: false,
+                        tags: Vec::new(),
+                        directive_invocations: Vec::new(),
+                    })
+                })
+                .collect::<IndexMap<_, _>>(),
+            visible: None,
+            inaccessible: false,
+            tags: Vec::new(),
+            rust_typename: Some(std::any::type_name::<Self>()),
+            directive_invocations: Vec::new(),
+            requires_scopes: Vec::new(),
+        })
+    }
+
+    // Variables are given as strings, literals as enum values
+    fn parse(value: Option<Value>) -> InputValueResult<Self> {
+        let value = value.unwrap_or_default();
+        let name = match &value {
+            Value::Enum(name) => name.as_str(),
+            Value::String(name) => name.as_str(),
+            _ => return Err(InputValueError::expected_type(value)),
+        };
+
+        TIMERANGES.iter()
+            .find(|timerange| Self::graphql_name(timerange.label) == name)
+            .map(|timerange| TimerangeLabel(timerange.label))
+            .ok_or_else(|| InputValueError::custom(format!("Unknown timerange {}", name)))
+    }
+
+    fn to_value(&self) -> Value {
+        Value::Enum(Name::new(Self::graphql_name(self.0)))
+    }
+
+    fn as_raw_value(&self) -> Option<&Self::RawValueType> {
+        Some(self)
+    }
+}
+
+// Direction of the candles, trends and structures
+#[derive(Enum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Bullish,
+    Bearish,
+}
+
+impl Direction {
+    // Value stored in the direction columns
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Direction::Bullish => "Bullish",
+            Direction::Bearish => "Bearish",
+        }
+    }
+}
+
+// Order of the rows on the time column of their table
+#[derive(Enum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SortOrder {
+    Asc,
+    #[default]
+    Desc,
+}
+
+impl SortOrder {
+    pub fn as_sql(&self) -> &'static str {
+        match self {
+            SortOrder::Asc => "ASC",
+            SortOrder::Desc => "DESC",
+        }
+    }
+}
+
+// Time bounds of a query, bound as TIMESTAMPTZ like the time columns
+// Rejected when the range is empty, instead of silently returning nothing
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TimeBounds {
+    pub min: Option<DateTime<Utc>>,
+    pub max: Option<DateTime<Utc>>,
+    pub min_inclusive: bool,
+    pub max_inclusive: bool,
+}
+
+impl TimeBounds {
+    pub fn new(min: Option<DateTime<Utc>>, max: Option<DateTime<Utc>>, min_inclusive: bool, max_inclusive: bool) -> Result<Self, String> {
+        // A single instant is only valid when both bounds include it
+        let empty = |min: &DateTime<Utc>, max: &DateTime<Utc>| min > max || (min == max && !(min_inclusive && max_inclusive));
+
+        if let Some((min, max)) = min.zip(max).filter(|(min, max)| empty(min, max)) {
+            return Err(format!("minTimestamp {} must be before maxTimestamp {}", min.to_rfc3339(), max.to_rfc3339()));
+        }
+
+        Ok(TimeBounds {
+            min,
+            max,
+            min_inclusive,
+            max_inclusive,
+        })
+    }
+
+    pub fn min_operator(&self) -> &'static str {
+        if self.min_inclusive { ">=" } else { ">" }
+    }
+
+    pub fn max_operator(&self) -> &'static str {
+        if self.max_inclusive { "<=" } else { "<" }
+    }
+}
+
+// Every symbol is registered in the symbols table before its data is inserted
+pub async fn symbol_exists(pool: &PgPool, symbol: &str) -> Result<bool, sqlx::Error> {
+    query_scalar::<_, bool>("SELECT EXISTS (SELECT 1 FROM symbols WHERE symbol = $1)")
+        .bind(symbol)
+        .fetch_one(pool)
+        .await
+}
+
+// Structure kinds are only known from the stored rows, of either structure table
+pub async fn structure_exists(pool: &PgPool, structure: &str) -> Result<bool, sqlx::Error> {
+    query_scalar::<_, bool>("SELECT EXISTS (SELECT 1 FROM one_d_structures WHERE structure = $1) OR EXISTS (SELECT 1 FROM two_d_structures WHERE structure = $1)")
+        .bind(structure)
+        .fetch_one(pool)
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{TimeBounds, TimerangeLabel};
+    use crate::TIMERANGES;
+
+    use async_graphql::{InputType, Name, Value};
+    use chrono::{TimeZone, Utc};
+
+    #[test]
+    fn bounds_keep_their_operators() {
+        let min = Utc.with_ymd_and_hms(2024, 1, 1, 8, 0, 0).unwrap();
+        let max = Utc.with_ymd_and_hms(2024, 1, 1, 9, 0, 0).unwrap();
+
+        let bounds = TimeBounds::new(Some(min), Some(max), true, false).unwrap();
+        assert_eq!((bounds.min_operator(), bounds.max_operator()), (">=", "<"));
+
+        let bounds = TimeBounds::new(Some(min), None, false, true).unwrap();
+        assert_eq!((bounds.min_operator(), bounds.max_operator()), (">", "<="));
+    }
+
+    #[test]
+    fn rejects_empty_ranges() {
+        let min = Utc.with_ymd_and_hms(2024, 1, 1, 8, 0, 0).unwrap();
+        let max = Utc.with_ymd_and_hms(2024, 1, 1, 9, 0, 0).unwrap();
+
+        assert!(TimeBounds::new(Some(max), Some(min), true, true).is_err());
+
+        // A single instant needs both bounds to include it
+        assert!(TimeBounds::new(Some(min), Some(min), true, true).is_ok());
+        assert!(TimeBounds::new(Some(min), Some(min), true, false).is_err());
+        assert!(TimeBounds::new(Some(min), Some(min), false, true).is_err());
+    }
+
+    #[test]
+    fn parses_the_timerange_enum() {
+        let label = TIMERANGES[0].label;
+        let name = format!("T_{}", label);
+
+        let parsed = TimerangeLabel::parse(Some(Value::Enum(Name::new(&name)))).unwrap();
+        assert_eq!(parsed.as_str(), label);
+        assert_eq!(parsed.to_value(), Value::Enum(Name::new(&name)));
+
+        // Variables are strings
+        assert_eq!(TimerangeLabel::parse(Some(Value::String(name))).unwrap(), parsed);
+
+        assert!(TimerangeLabel::parse(Some(Value::Enum(Name::new(label)))).is_err());
+        assert!(TimerangeLabel::parse(Some(Value::Number(1.into()))).is_err());
+        assert!(TimerangeLabel::parse(None).is_err());
+    }
+}
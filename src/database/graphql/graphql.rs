@@ -1,29 +1,180 @@
-use crate::database::{
-    graphql::{mutation::MutationRoot, query::QueryRoot},
-    structures::Permission
+use crate::{
+    database::{
+        auth::auth_access,
+        graphql::{mutation::MutationRoot, query::QueryRoot, subscription::SubscriptionRoot},
+        structures::Permission
+    },
+    metrics::structures::Metrics,
+    utils::auth::verify_jwt
 };
 
 use async_graphql::{
-    EmptySubscription,
-    http::GraphiQLSource,
-    Schema
-    
+    async_trait,
+    Data,
+    Error,
+    extensions::{Extension, ExtensionContext, ExtensionFactory, NextResolve, ResolveInfo},
+    http::{GraphiQLSource, ALL_WEBSOCKET_PROTOCOLS},
+    Schema,
+    ServerResult,
+    Value as GraphQLValue
 };
-use async_graphql_axum::{GraphQLRequest, GraphQLResponse};
+use async_graphql_axum::{GraphQLProtocol, GraphQLRequest, GraphQLResponse, GraphQLWebSocket};
 use axum::{
-    extract::State,
+    extract::{State, WebSocketUpgrade},
     response::{self, IntoResponse},
 };
-use std::sync::Arc;
+use serde_json::Value;
+use std::{
+    sync::Arc,
+    time::Instant
+};
+
+// Root fields recorded as a metric label
+// Anything else (introspection) is counted as other, to keep the number of series bounded
+const KNOWN_OPERATIONS: [&str; 14] = [
+    "get",
+    "candles",
+    "sessions",
+    "trends",
+    "oneDStructures",
+    "twoDStructures",
+    "aggregatedCandles",
+    "candlesConnection",
+    "sessionsConnection",
+    "trendsConnection",
+    "oneDStructuresConnection",
+    "twoDStructuresConnection",
+    "post",
+    "backfillCandles",
+];
 
 pub async fn graphiql() -> impl IntoResponse {
-    response::Html(GraphiQLSource::build().endpoint("/data").finish())
+    response::Html(GraphiQLSource::build().endpoint("/data").subscription_endpoint("/data/ws").finish())
 }
 
-pub async fn graphql_handler(schema: State<Arc<Schema<QueryRoot, MutationRoot, EmptySubscription>>>, Permission(permission): Permission, req: GraphQLRequest) -> GraphQLResponse {
+pub async fn graphql_handler(schema: State<Arc<Schema<QueryRoot, MutationRoot, SubscriptionRoot>>>, Permission(permission): Permission, req: GraphQLRequest) -> GraphQLResponse {
     // Share the permission level with the request
     let mut request = req.into_inner();
     request = request.data(permission.clone());
-    
+
     schema.execute(request).await.into()
 }
+
+// Record the latency of every root field executed
+// The operation name is chosen by the client, the root fields are not
+pub struct OperationMetrics;
+
+impl ExtensionFactory for OperationMetrics {
+    fn create(&self) -> Arc<dyn Extension> {
+        Arc::new(OperationMetricsExtension)
+    }
+}
+
+struct OperationMetricsExtension;
+
+#[async_trait::async_trait]
+impl Extension for OperationMetricsExtension {
+    async fn resolve(&self, ctx: &ExtensionContext<'_>, info: ResolveInfo<'_>, next: NextResolve<'_>) -> ServerResult<Option<GraphQLValue>> {
+        // A root field resolves its whole selection
+        if info.path_node.parent.is_some() {
+            return next.run(ctx, info).await;
+        }
+
+        let operation = operation_label(info.name);
+        let started = Instant::now();
+
+        let result = next.run(ctx, info).await;
+
+        Metrics::global().graphql_duration
+            .with_label_values(&[operation])
+            .observe(started.elapsed().as_secs_f64());
+
+        result
+    }
+}
+
+fn operation_label(field: &str) -> &'static str {
+    KNOWN_OPERATIONS.iter()
+        .find(|known| **known == field)
+        .copied()
+        .unwrap_or("other")
+}
+
+pub async fn graphql_subscription_handler(State(schema): State<Arc<Schema<QueryRoot, MutationRoot, SubscriptionRoot>>>, protocol: GraphQLProtocol, upgrade: WebSocketUpgrade) -> impl IntoResponse {
+    let schema = schema.as_ref().clone();
+
+    upgrade
+        .protocols(ALL_WEBSOCKET_PROTOCOLS)
+        .on_upgrade(move |stream| {
+            GraphQLWebSocket::new(stream, schema, protocol)
+                .on_connection_init(on_connection_init)
+                .serve()
+        })
+}
+
+// Browsers can't set headers on a websocket
+// So the token is sent in the connection_init payload instead
+pub async fn on_connection_init(payload: Value) -> Result<Data, Error> {
+    let header = payload.get("Authorization")
+        .or_else(|| payload.get("authorization"))
+        .and_then(|header| header.as_str())
+        .ok_or(Error::from("Missing Authorization payload"))?;
+
+    let token = header.strip_prefix("Bearer ")
+        .ok_or(Error::from("Invalid Authorization payload"))?;
+
+    let token_data = verify_jwt(token)
+        .map_err(|_| Error::from("Invalid token"))?;
+
+    let permission = auth_access(token_data.claims)
+        .map_err(|_| Error::from("Permission denied"))?;
+
+    // Share the permission level with every subscription of the connection
+    let mut data = Data::default();
+    data.insert(permission);
+
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{operation_label, OperationMetrics};
+    use crate::metrics::structures::Metrics;
+
+    use async_graphql::{EmptyMutation, EmptySubscription, Object, Schema};
+
+    struct Query;
+
+    #[Object]
+    impl Query {
+        async fn candles(&self) -> i32 {
+            1
+        }
+    }
+
+    fn count(operation: &str) -> u64 {
+        Metrics::global().graphql_duration
+            .with_label_values(&[operation])
+            .get_sample_count()
+    }
+
+    #[test]
+    fn labels_are_bounded() {
+        assert_eq!(operation_label("candlesConnection"), "candlesConnection");
+        assert_eq!(operation_label("__schema"), "other");
+        assert_eq!(operation_label("GetCandles"), "other");
+    }
+
+    #[tokio::test]
+    async fn records_the_executed_root_field() {
+        let schema = Schema::build(Query, EmptyMutation, EmptySubscription)
+            .extension(OperationMetrics)
+            .finish();
+
+        let before = count("candles");
+        let response = schema.execute("query GetCandles { candles }").await;
+
+        assert!(response.errors.is_empty());
+        assert_eq!(count("candles"), before + 1);
+    }
+}
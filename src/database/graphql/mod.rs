@@ -1,3 +1,11 @@
+pub mod aggregation;
+pub mod bulk;
+pub mod filters;
 pub mod graphql;
 pub mod mutation;
-pub mod query;
\ No newline at end of file
+pub mod pagination;
+pub mod query;
+pub mod selection;
+pub mod subscription;
+pub mod upsert;
+pub mod validation;
\ No newline at end of file
@@ -1,6 +1,40 @@
-use crate::database::{
-    database::perform_insert,
-    structures::PermissionLevel
+use crate::{
+    database::{
+        database::{deadline_error, is_retriable, perform_insert, record_insert, InsertError, InsertTarget, Inserted},
+        graphql::{
+            bulk::backfill_candles,
+            subscription::{DataFeed, publish},
+            upsert::{
+                dedup_by_key,
+                push_upsert,
+                ConflictMode,
+                EntityResult,
+                Upsert,
+                Upserted,
+                UpsertTable,
+                CANDLE_UPSERT,
+                ONE_D_STRUCTURE_UPSERT,
+                SESSION_UPSERT,
+                TREND_UPSERT,
+                TWO_D_STRUCTURE_UPSERT
+            },
+            validation::{
+                log_rejected,
+                validate_candle,
+                validate_one_d_structure,
+                validate_rows,
+                validate_session,
+                validate_trend,
+                validate_two_d_structure
+            }
+        },
+        structures::{PermissionLevel, RetryPolicy}
+    },
+    Candle,
+    OneDStructures,
+    Session,
+    Trend,
+    TwoDStructures
 };
 use common::{
     entities::{
@@ -15,16 +49,59 @@ use common::{
     utils::log::{LogFile, LogLevel}
 };
 
-use async_graphql::{Context, Error, Object};
-use sqlx::{PgPool, Postgres, QueryBuilder};
-use std::sync::Arc;
+use async_graphql::{Context, Enum, Error, Object, SimpleObject};
+use sqlx::{
+    FromRow,
+    PgPool,
+    postgres::PgRow,
+    Postgres,
+    QueryBuilder
+};
+use std::{
+    sync::Arc,
+    time::Instant
+};
+use tokio::{
+    sync::broadcast::Sender,
+    task::JoinError,
+    time::{sleep, timeout}
+};
+use tracing::Instrument;
 
 // Main GraphQL mutation root
 pub struct MutationRoot;
 
+// How the entities of a post are written
+#[derive(Enum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PostMode {
+    // One transaction, all committed or all rolled back
+    #[default]
+    Atomic,
+    // Concurrent inserts, each entity committed on its own
+    Parallel,
+}
+
+// What each entity insert did with the rows received
+// A failed entity doesn't hide the others, each one carries its own error
+#[derive(SimpleObject, Clone, Debug, Default)]
+pub struct PostResult {
+    // Every entity was written
+    // In parallel mode the rejected rows are left out, in atomic mode they refuse the whole payload
+    pub committed: bool,
+    pub elapsed_ms: u64,
+    pub candles: EntityResult,
+    pub sessions: EntityResult,
+    pub trends: EntityResult,
+    pub one_d_structures: EntityResult,
+    pub two_d_structures: EntityResult,
+}
+
 #[Object]
 impl MutationRoot {
-    pub async fn post(&self, ctx: &Context<'_>, data: DatabaseData) -> Result<bool, Error> {
+    // Rows are matched on their natural key
+    // So a retried or replayed batch doesn't create duplicates
+    // By default the whole payload is written or nothing is
+    pub async fn post(&self, ctx: &Context<'_>, data: DatabaseData, #[graphql(default)] on_conflict: ConflictMode, #[graphql(default)] mode: PostMode) -> Result<PostResult, Error> {
         let permission = ctx.data::<PermissionLevel>()?;
         if *permission != PermissionLevel::Admin {
             return Err(Error::from("Permission denied"));
@@ -33,92 +110,399 @@ impl MutationRoot {
         let pool = ctx.data::<PgPool>()?;
         let pool = Arc::new(pool.clone());
 
-        let candles = data.candles;
-        let candle_insertion = tokio::spawn({
-            let pool = Arc::clone(&pool);
+        let feed = ctx.data::<DataFeed>()?;
+        let retry = *ctx.data::<RetryPolicy>()?;
 
-            async move {
-                insert_candles(pool, &candles).await
-            }
-        });
-        
-        let sessions = data.sessions;
-        let session_insertion = tokio::spawn({
-            let pool = Arc::clone(&pool);
+        match mode {
+            PostMode::Atomic => post_atomic(pool, retry, feed, data, on_conflict).await,
+            PostMode::Parallel => post_parallel(pool, retry, feed, data, on_conflict).await,
+        }
+    }
 
-            async move {
-                insert_sessions(pool, &sessions).await
-            }
-        });
+    // Historical candles in bulk, through COPY instead of bind parameters
+    // They are not forwarded to the subscribers
+    pub async fn backfill_candles(&self, ctx: &Context<'_>, candles: Vec<CandleInput>, #[graphql(default)] on_conflict: ConflictMode) -> Result<EntityResult, Error> {
+        let permission = ctx.data::<PermissionLevel>()?;
+        if *permission != PermissionLevel::Admin {
+            return Err(Error::from("Permission denied"));
+        }
 
-        let trends = data.trends;
-        let trend_insertion = tokio::spawn({
-            let pool = Arc::clone(&pool);
+        let pool = ctx.data::<PgPool>()?;
 
-            async move {
-                insert_trends(pool, &trends).await
-            }
-        });
+        Ok(backfill_candles(pool, &candles, on_conflict).await)
+    }
+}
 
-        let one_d_structures = data.one_d_structure;
-        let one_d_structure_insertion = tokio::spawn({
-            let pool = Arc::clone(&pool);
+// Every entity in one transaction
+// The subscribers only get the rows once they are all committed
+// The whole transaction is retried following the policy
+async fn post_atomic(pool: Arc<PgPool>, retry: RetryPolicy, feed: &DataFeed, data: DatabaseData, on_conflict: ConflictMode) -> Result<PostResult, Error> {
+    let started = Instant::now();
 
-            async move {
-                insert_one_d_structures(pool, &one_d_structures).await
-            }
-        });
+    // A rejected row would otherwise be left out of a committed payload
+    // So the transaction isn't started unless every row is valid
+    if let Some(result) = refuse_payload(&data, started) {
+        LogFile::add_log(LogLevel::Error, "Refused data with rejected rows").ok();
 
-        let two_d_structures = data.two_d_structure;
-        let two_d_structure_insertion = tokio::spawn({
-            let pool = Arc::clone(&pool);
+        return Ok(result);
+    }
 
-            async move {
-                insert_two_d_structures(pool, &two_d_structures).await
-            }
+    let mut retries = 0;
+
+    loop {
+        // Dropping a cut attempt rolls its transaction back
+        let attempt = match timeout(retry.remaining(started.elapsed()), write_atomic(&pool, &data, on_conflict)).await {
+            Ok(attempt) => attempt,
+            Err(_) => Err(AtomicFailure::new("Failed to write data", deadline_error())),
+        };
+
+        let failure = match attempt {
+            Ok(upserts) => return Ok(upserts.committed(feed, retries, started)),
+            Err(failure) => failure,
+        };
+
+        let attempt = retries + 1;
+        let backoff = retry.backoff(retries);
+
+        if let Some(reason) = retry.give_up(failure.retriable, retries, started.elapsed(), backoff) {
+            LogFile::add_log(LogLevel::Error, &format!("{} on attempt {}, {}", failure.message, attempt, reason)).ok();
+
+            return match failure.upserts {
+                Some(upserts) => Ok(upserts.rolled_back(retries, started)),
+                None => Err(Error::from(failure.message)),
+            };
+        }
+
+        LogFile::add_log(LogLevel::Error, &format!("{} on attempt {}, retrying in {:?}", failure.message, attempt, backoff)).ok();
+
+        sleep(backoff).await;
+
+        retries += 1;
+    }
+}
+
+// The upserts of every entity in one transaction
+struct AtomicUpserts {
+    candles: Upsert<Candle>,
+    sessions: Upsert<Session>,
+    trends: Upsert<Trend>,
+    one_d_structures: Upsert<OneDStructures>,
+    two_d_structures: Upsert<TwoDStructures>,
+}
+
+// A failed attempt of an atomic post, nothing of it is kept
+struct AtomicFailure {
+    message: String,
+    retriable: bool,
+    // Set when an insert failed, so every entity can be reported
+    upserts: Option<AtomicUpserts>,
+}
+
+impl AtomicFailure {
+    fn new(context: &str, error: sqlx::Error) -> Self {
+        AtomicFailure {
+            message: format!("{}: {}", context, error),
+            retriable: is_retriable(&error),
+            upserts: None,
+        }
+    }
+}
+
+// One attempt, begin to commit
+async fn write_atomic(pool: &PgPool, data: &DatabaseData, on_conflict: ConflictMode) -> Result<AtomicUpserts, AtomicFailure> {
+    let mut transaction = pool.begin().await
+        .map_err(|e| AtomicFailure::new("Failed to start transaction", e))?;
+
+    // The inserts run one after the other, a transaction has a single connection
+    // Once one fails the transaction is aborted, so the next ones are not sent
+    let candles = insert_candles(InsertTarget::Transaction(&mut transaction), &data.candles, on_conflict).await;
+    let mut failed = candles.is_failed();
+
+    let sessions = if failed {
+        Upsert::not_attempted(data.sessions.len())
+    } else {
+        insert_sessions(InsertTarget::Transaction(&mut transaction), &data.sessions, on_conflict).await
+    };
+    failed |= sessions.is_failed();
+
+    let trends = if failed {
+        Upsert::not_attempted(data.trends.len())
+    } else {
+        insert_trends(InsertTarget::Transaction(&mut transaction), &data.trends, on_conflict).await
+    };
+    failed |= trends.is_failed();
+
+    let one_d_structures = if failed {
+        Upsert::not_attempted(data.one_d_structure.len())
+    } else {
+        insert_one_d_structures(InsertTarget::Transaction(&mut transaction), &data.one_d_structure, on_conflict).await
+    };
+    failed |= one_d_structures.is_failed();
+
+    let two_d_structures = if failed {
+        Upsert::not_attempted(data.two_d_structure.len())
+    } else {
+        insert_two_d_structures(InsertTarget::Transaction(&mut transaction), &data.two_d_structure, on_conflict).await
+    };
+    failed |= two_d_structures.is_failed();
+
+    let upserts = AtomicUpserts { candles, sessions, trends, one_d_structures, two_d_structures };
+
+    if failed {
+        transaction.rollback().await.ok();
+
+        // Only the insert that failed can be retriable, the next ones were not attempted
+        let retriable = upserts.candles.retriable
+            || upserts.sessions.retriable
+            || upserts.trends.retriable
+            || upserts.one_d_structures.retriable
+            || upserts.two_d_structures.retriable;
+
+        return Err(AtomicFailure {
+            message: "Rolled back data after a failed insert".to_string(),
+            retriable,
+            upserts: Some(upserts),
         });
+    }
 
-       let result = tokio::try_join!(
-            candle_insertion,
-            session_insertion,
-            trend_insertion,
-            one_d_structure_insertion,
-            two_d_structure_insertion
-        );
-
-        match result {
-            Ok((candle_res, session_res, trend_res, one_d_res, two_d_res)) => {
-                for (name, res) in [
-                    ("candles", &candle_res),
-                    ("sessions", &session_res),
-                    ("trends", &trend_res),
-                    ("one_d_structure", &one_d_res),
-                    ("two_d_structure", &two_d_res),
-                ] {
-                    if let Err(e) = res {
-                        LogFile::add_log(LogLevel::Error, &format!("Failed to insert {}: {:?}", name, e)).ok();
-                        return Err(Error::from(format!("Failed to insert {}: {:?}", name, e)));
-                    }
-                }
-
-                Ok(true)
-            }
+    transaction.commit().await
+        .map_err(|e| AtomicFailure::new("Failed to commit data", e))?;
+
+    Ok(upserts)
+}
+
+impl AtomicUpserts {
+    // Record the committed rows and forward them to the subscribers
+    fn committed(self, feed: &DataFeed, retries: u32, started: Instant) -> PostResult {
+        for (table, rows) in [
+            ("candles", self.candles.rows.len()),
+            ("sessions", self.sessions.rows.len()),
+            ("trends", self.trends.rows.len()),
+            ("one_d_structures", self.one_d_structures.rows.len()),
+            ("two_d_structures", self.two_d_structures.rows.len()),
+        ] {
+            record_insert(table, rows);
+        }
+
+        PostResult {
+            committed: true,
+            elapsed_ms: started.elapsed().as_millis() as u64,
+            candles: with_retries(publish_upsert(&feed.candles, self.candles), retries),
+            sessions: with_retries(publish_upsert(&feed.sessions, self.sessions), retries),
+            trends: with_retries(publish_upsert(&feed.trends, self.trends), retries),
+            one_d_structures: with_retries(publish_upsert(&feed.one_d_structures, self.one_d_structures), retries),
+            two_d_structures: with_retries(publish_upsert(&feed.two_d_structures, self.two_d_structures), retries),
+        }
+    }
+
+    // Nothing of the payload is kept
+    fn rolled_back(self, retries: u32, started: Instant) -> PostResult {
+        PostResult {
+            committed: false,
+            elapsed_ms: started.elapsed().as_millis() as u64,
+            candles: with_retries(self.candles.result.rolled_back(), retries),
+            sessions: with_retries(self.sessions.result.rolled_back(), retries),
+            trends: with_retries(self.trends.result.rolled_back(), retries),
+            one_d_structures: with_retries(self.one_d_structures.result.rolled_back(), retries),
+            two_d_structures: with_retries(self.two_d_structures.result.rolled_back(), retries),
+        }
+    }
+}
+
+// Every entity is sent again when the transaction is retried
+fn with_retries(result: EntityResult, retries: u32) -> EntityResult {
+    EntityResult { retries, ..result }
+}
+
+// The result of a payload with rejected rows, None when every row is valid
+fn refuse_payload(data: &DatabaseData, started: Instant) -> Option<PostResult> {
+    let candles = validate_rows(&data.candles, validate_candle).1;
+    let sessions = validate_rows(&data.sessions, validate_session).1;
+    let trends = validate_rows(&data.trends, validate_trend).1;
+    let one_d_structures = validate_rows(&data.one_d_structure, validate_one_d_structure).1;
+    let two_d_structures = validate_rows(&data.two_d_structure, validate_two_d_structure).1;
+
+    let rejected = [&candles, &sessions, &trends, &one_d_structures, &two_d_structures]
+        .iter()
+        .any(|rejected| !rejected.is_empty());
+
+    if !rejected {
+        return None;
+    }
+
+    log_rejected("candles", &candles);
+    log_rejected("sessions", &sessions);
+    log_rejected("trends", &trends);
+    log_rejected("one_d_structures", &one_d_structures);
+    log_rejected("two_d_structures", &two_d_structures);
+
+    Some(PostResult {
+        committed: false,
+        elapsed_ms: started.elapsed().as_millis() as u64,
+        candles: EntityResult::refused(data.candles.len(), candles),
+        sessions: EntityResult::refused(data.sessions.len(), sessions),
+        trends: EntityResult::refused(data.trends.len(), trends),
+        one_d_structures: EntityResult::refused(data.one_d_structure.len(), one_d_structures),
+        two_d_structures: EntityResult::refused(data.two_d_structure.len(), two_d_structures),
+    })
+}
+
+// Every entity on its own connection, concurrently
+// An entity that fails doesn't undo the others
+async fn post_parallel(pool: Arc<PgPool>, retry: RetryPolicy, feed: &DataFeed, data: DatabaseData, on_conflict: ConflictMode) -> Result<PostResult, Error> {
+    let started = Instant::now();
+
+    let candles = data.candles;
+    let candle_count = candles.len();
+    let candle_insertion = tokio::spawn({
+        let pool = Arc::clone(&pool);
+        let feed = feed.candles.clone();
+
+        async move {
+            // Forward the committed rows to the subscribers
+            let upsert = insert_candles(InsertTarget::Pool(pool, retry), &candles, on_conflict).await;
+
+            publish_upsert(&feed, upsert)
+        }.in_current_span()
+    });
+    
+    let sessions = data.sessions;
+    let session_count = sessions.len();
+    let session_insertion = tokio::spawn({
+        let pool = Arc::clone(&pool);
+        let feed = feed.sessions.clone();
+
+        async move {
+            let upsert = insert_sessions(InsertTarget::Pool(pool, retry), &sessions, on_conflict).await;
+
+            publish_upsert(&feed, upsert)
+        }.in_current_span()
+    });
+
+    let trends = data.trends;
+    let trend_count = trends.len();
+    let trend_insertion = tokio::spawn({
+        let pool = Arc::clone(&pool);
+        let feed = feed.trends.clone();
+
+        async move {
+            let upsert = insert_trends(InsertTarget::Pool(pool, retry), &trends, on_conflict).await;
+
+            publish_upsert(&feed, upsert)
+        }.in_current_span()
+    });
+
+    let one_d_structures = data.one_d_structure;
+    let one_d_structure_count = one_d_structures.len();
+    let one_d_structure_insertion = tokio::spawn({
+        let pool = Arc::clone(&pool);
+        let feed = feed.one_d_structures.clone();
+
+        async move {
+            let upsert = insert_one_d_structures(InsertTarget::Pool(pool, retry), &one_d_structures, on_conflict).await;
+
+            publish_upsert(&feed, upsert)
+        }.in_current_span()
+    });
+
+    let two_d_structures = data.two_d_structure;
+    let two_d_structure_count = two_d_structures.len();
+    let two_d_structure_insertion = tokio::spawn({
+        let pool = Arc::clone(&pool);
+        let feed = feed.two_d_structures.clone();
+
+        async move {
+            let upsert = insert_two_d_structures(InsertTarget::Pool(pool, retry), &two_d_structures, on_conflict).await;
+
+            publish_upsert(&feed, upsert)
+        }.in_current_span()
+    });
+
+    // Wait for every insert, so all the failures are reported
+    let (candle_res, session_res, trend_res, one_d_res, two_d_res) = tokio::join!(
+        candle_insertion,
+        session_insertion,
+        trend_insertion,
+        one_d_structure_insertion,
+        two_d_structure_insertion
+    );
+
+    let mut result = PostResult {
+        committed: false,
+        elapsed_ms: started.elapsed().as_millis() as u64,
+        candles: task_result("candles", candle_count, candle_res),
+        sessions: task_result("sessions", session_count, session_res),
+        trends: task_result("trends", trend_count, trend_res),
+        one_d_structures: task_result("one_d_structures", one_d_structure_count, one_d_res),
+        two_d_structures: task_result("two_d_structures", two_d_structure_count, two_d_res),
+    };
+
+    result.committed = [&result.candles, &result.sessions, &result.trends, &result.one_d_structures, &result.two_d_structures]
+        .iter()
+        .all(|entity| entity.error.is_none());
+
+    Ok(result)
+}
+
+// Forward the written rows to the subscribers and keep the result
+fn publish_upsert<T>(feed: &Sender<T>, upsert: Upsert<T>) -> EntityResult {
+    publish(feed, upsert.rows);
+
+    upsert.result
+}
+
+// A panicked insert task is reported like a failed insert
+fn task_result(name: &str, received: usize, res: Result<EntityResult, JoinError>) -> EntityResult {
+    res.unwrap_or_else(|e| {
+        LogFile::add_log(LogLevel::Error, &format!("Failed to insert {}: {}", name, e)).ok();
+
+        EntityResult {
+            received,
+            error: Some(format!("Insert task failed: {}", e)),
+            ..EntityResult::default()
+        }
+    })
+}
+
+// Insert the rows in chunks that stay under the bind parameter limit
+// A failed chunk stops the next ones, on the pool the chunks before it stay committed
+// The retry deadline runs from the first chunk, so a large batch can't retry for longer
+async fn insert_chunks<'s, R, T, F>(mut target: InsertTarget<'_>, table: &UpsertTable, rows: &'s [R], build_query_builder: F) -> (Inserted<Upserted<T>>, Option<InsertError>)
+where T: for<'r> FromRow<'r, PgRow> + Send + Unpin, F: Fn(&'s [R]) -> QueryBuilder<'s, Postgres> {
+    let started = Instant::now();
+    let mut inserted = Inserted { rows: Vec::new(), retries: 0 };
+
+    for chunk in rows.chunks(table.chunk_size()) {
+        match perform_insert(target.reborrow(), table.name, started, || build_query_builder(chunk)).await {
+            Ok(chunk_inserted) => {
+                inserted.rows.extend(chunk_inserted.rows);
+                inserted.retries += chunk_inserted.retries;
+            },
             Err(e) => {
-                LogFile::add_log(LogLevel::Error, &format!("Failed to insert data: {}", e)).ok();
-                Err(Error::from(format!("Failed to insert data: {}", e)))
+                let error = InsertError { error: e.error, retries: inserted.retries + e.retries };
+
+                return (inserted, Some(error));
             }
         }
     }
+
+    (inserted, None)
 }
 
-pub async fn insert_candles(pool: Arc<PgPool>, candles: &[CandleInput]) -> Result<(), Error> {
-    if candles.is_empty() {
-        return Ok(());
+pub async fn insert_candles(target: InsertTarget<'_>, candles: &[CandleInput], mode: ConflictMode) -> Upsert<Candle> {
+    let started = Instant::now();
+    let (valid, rejected) = validate_rows(candles, validate_candle);
+    let valid = dedup_by_key(&valid, |candle| (&candle.symbol, &candle.timerange, candle.timestamp));
+
+    log_rejected("candles", &rejected);
+
+    if valid.is_empty() {
+        return Upsert::new(candles.len(), rejected, Inserted { rows: Vec::new(), retries: 0 }, started);
     }
 
-    let res = perform_insert(pool, || {
+    let (inserted, error) = insert_chunks(target, &CANDLE_UPSERT, &valid, |chunk| {
         let mut query_builder: QueryBuilder<Postgres> = QueryBuilder::new("INSERT INTO candles (symbol, timerange, timestamp, open, high, close, low, volume, direction) ");
-        query_builder.push_values(candles.iter(), |mut b, candle| {
+        query_builder.push_values(chunk, |mut b, candle| {
             b.push_bind(&candle.symbol)
             .push_bind(&candle.timerange)
             .push_bind(&candle.timestamp)
@@ -129,29 +513,39 @@ pub async fn insert_candles(pool: Arc<PgPool>, candles: &[CandleInput]) -> Resul
             .push_bind(&candle.volume)
             .push_bind(&candle.direction);
         });
+        push_upsert(&mut query_builder, &CANDLE_UPSERT, mode, "symbol, timerange, timestamp, open, high, low, close, volume, direction");
 
         query_builder
     }).await;
 
-    if let Err(e) = res {
-        LogFile::add_log(LogLevel::Error, &format!("Failed to insert candles: {}", e)).ok();
+    match error {
+        None => {
+            LogFile::add_log(LogLevel::Info, "Candles inserted successfully").ok();
 
-        Err(Error::from(format!("Failed to insert candles: {}", e)))
-    } else {
-        LogFile::add_log(LogLevel::Info, "Candles inserted successfully").ok();
+            Upsert::new(candles.len(), rejected, inserted, started)
+        }
+        Some(e) => {
+            LogFile::add_log(LogLevel::Error, &format!("Failed to insert candles: {}", e)).ok();
 
-        Ok(())
+            Upsert::failed(candles.len(), rejected, inserted, e, started)
+        }
     }
 }
 
-pub async fn insert_sessions(pool: Arc<PgPool>, sessions: &[SessionInput]) -> Result<(), Error> {
-    if sessions.is_empty() {
-        return Ok(());
+pub async fn insert_sessions(target: InsertTarget<'_>, sessions: &[SessionInput], mode: ConflictMode) -> Upsert<Session> {
+    let started = Instant::now();
+    let (valid, rejected) = validate_rows(sessions, validate_session);
+    let valid = dedup_by_key(&valid, |session| (&session.symbol, &session.label, session.start_time));
+
+    log_rejected("sessions", &rejected);
+
+    if valid.is_empty() {
+        return Upsert::new(sessions.len(), rejected, Inserted { rows: Vec::new(), retries: 0 }, started);
     }
 
-    let res = perform_insert(pool, || {
+    let (inserted, error) = insert_chunks(target, &SESSION_UPSERT, &valid, |chunk| {
         let mut query_builder: QueryBuilder<Postgres> = QueryBuilder::new("INSERT INTO sessions (symbol, label, start_time, end_time, high, low, open, close, volume)");
-        query_builder.push_values(sessions.iter(), |mut b, session| {
+        query_builder.push_values(chunk, |mut b, session| {
             b.push_bind(&session.symbol)
              .push_bind(&session.label)
              .push_bind(&session.start_time)
@@ -162,29 +556,39 @@ pub async fn insert_sessions(pool: Arc<PgPool>, sessions: &[SessionInput]) -> Re
              .push_bind(&session.close)
              .push_bind(&session.volume);
         });
+        push_upsert(&mut query_builder, &SESSION_UPSERT, mode, "symbol, label, start_time, end_time, high, low, open, close, volume");
 
         query_builder
     }).await;
 
-    if let Err(e) = res {
-        LogFile::add_log(LogLevel::Error, &format!("Failed to insert sessions: {}", e)).ok();
+    match error {
+        None => {
+            LogFile::add_log(LogLevel::Info, "Sessions inserted successfully").ok();
 
-        Err(Error::from(format!("Failed to insert sessions: {}", e)))
-    } else {
-        LogFile::add_log(LogLevel::Info, "Sessions inserted successfully").ok();
+            Upsert::new(sessions.len(), rejected, inserted, started)
+        }
+        Some(e) => {
+            LogFile::add_log(LogLevel::Error, &format!("Failed to insert sessions: {}", e)).ok();
 
-        Ok(())
+            Upsert::failed(sessions.len(), rejected, inserted, e, started)
+        }
     }
 }
 
-pub async fn insert_trends(pool: Arc<PgPool>, trends: &[TrendInput]) -> Result<(), Error> {
-    if trends.is_empty() {
-        return Ok(());
+pub async fn insert_trends(target: InsertTarget<'_>, trends: &[TrendInput], mode: ConflictMode) -> Upsert<Trend> {
+    let started = Instant::now();
+    let (valid, rejected) = validate_rows(trends, validate_trend);
+    let valid = dedup_by_key(&valid, |trend| (&trend.symbol, &trend.timerange, trend.start_time));
+
+    log_rejected("trends", &rejected);
+
+    if valid.is_empty() {
+        return Upsert::new(trends.len(), rejected, Inserted { rows: Vec::new(), retries: 0 }, started);
     }
 
-    let res = perform_insert(pool, || {
+    let (inserted, error) = insert_chunks(target, &TREND_UPSERT, &valid, |chunk| {
         let mut query_builder: QueryBuilder<Postgres> = QueryBuilder::new("INSERT INTO trends (symbol, timerange, start_time, end_time, direction, high, low)");
-        query_builder.push_values(trends.iter(), |mut b, trend| {
+        query_builder.push_values(chunk, |mut b, trend| {
             b.push_bind(&trend.symbol)
              .push_bind(&trend.timerange)
              .push_bind(&trend.start_time)
@@ -193,29 +597,39 @@ pub async fn insert_trends(pool: Arc<PgPool>, trends: &[TrendInput]) -> Result<(
              .push_bind(&trend.high)
              .push_bind(&trend.low);
         });
+        push_upsert(&mut query_builder, &TREND_UPSERT, mode, "symbol, timerange, start_time, end_time, direction, high, low");
 
         query_builder
     }).await;
 
-    if let Err(e) = res {
-        LogFile::add_log(LogLevel::Error, &format!("Failed to insert trends: {}", e)).ok();
+    match error {
+        None => {
+            LogFile::add_log(LogLevel::Info, "Trends inserted successfully").ok();
 
-        Err(Error::from(format!("Failed to insert trends: {}", e)))
-    } else {
-        LogFile::add_log(LogLevel::Info, "Trends inserted successfully").ok();
+            Upsert::new(trends.len(), rejected, inserted, started)
+        }
+        Some(e) => {
+            LogFile::add_log(LogLevel::Error, &format!("Failed to insert trends: {}", e)).ok();
 
-        Ok(())
+            Upsert::failed(trends.len(), rejected, inserted, e, started)
+        }
     }
 }
 
-pub async fn insert_one_d_structures(pool: Arc<PgPool>, structures: &[OneDStructuresInput]) -> Result<(), Error> {
-    if structures.is_empty() {
-        return Ok(());
+pub async fn insert_one_d_structures(target: InsertTarget<'_>, structures: &[OneDStructuresInput], mode: ConflictMode) -> Upsert<OneDStructures> {
+    let started = Instant::now();
+    let (valid, rejected) = validate_rows(structures, validate_one_d_structure);
+    let valid = dedup_by_key(&valid, |structure| (&structure.symbol, &structure.structure, &structure.timerange, structure.timestamp));
+
+    log_rejected("one_d_structures", &rejected);
+
+    if valid.is_empty() {
+        return Upsert::new(structures.len(), rejected, Inserted { rows: Vec::new(), retries: 0 }, started);
     }
 
-    let res = perform_insert(pool, || {
+    let (inserted, error) = insert_chunks(target, &ONE_D_STRUCTURE_UPSERT, &valid, |chunk| {
         let mut query_builder: QueryBuilder<Postgres> = QueryBuilder::new("INSERT INTO one_d_structures (symbol, structure, timerange, timestamp, price, direction)");
-        query_builder.push_values(structures.iter(), |mut b, structure| {
+        query_builder.push_values(chunk, |mut b, structure| {
             b.push_bind(&structure.symbol)
              .push_bind(&structure.structure)
              .push_bind(&structure.timerange)
@@ -223,29 +637,39 @@ pub async fn insert_one_d_structures(pool: Arc<PgPool>, structures: &[OneDStruct
              .push_bind(&structure.price)
              .push_bind(&structure.direction);
         });
+        push_upsert(&mut query_builder, &ONE_D_STRUCTURE_UPSERT, mode, "symbol, structure, timerange, timestamp, price, direction");
 
         query_builder
     }).await;
 
-    if let Err(e) = res {
-        LogFile::add_log(LogLevel::Error, &format!("Failed to insert one_d_structures: {}", e)).ok();
+    match error {
+        None => {
+            LogFile::add_log(LogLevel::Info, "OneD structures inserted successfully").ok();
 
-        Err(Error::from(format!("Failed to insert one_d_structures: {}", e)))
-    } else {
-        LogFile::add_log(LogLevel::Info, "OneD structures inserted successfully").ok();
+            Upsert::new(structures.len(), rejected, inserted, started)
+        }
+        Some(e) => {
+            LogFile::add_log(LogLevel::Error, &format!("Failed to insert one_d_structures: {}", e)).ok();
 
-        Ok(())
+            Upsert::failed(structures.len(), rejected, inserted, e, started)
+        }
     }
 }
 
-pub async fn insert_two_d_structures(pool: Arc<PgPool>, structures: &[TwoDStructuresInput]) -> Result<(), Error> {
-    if structures.is_empty() {
-        return Ok(());
+pub async fn insert_two_d_structures(target: InsertTarget<'_>, structures: &[TwoDStructuresInput], mode: ConflictMode) -> Upsert<TwoDStructures> {
+    let started = Instant::now();
+    let (valid, rejected) = validate_rows(structures, validate_two_d_structure);
+    let valid = dedup_by_key(&valid, |structure| (&structure.symbol, &structure.structure, &structure.timerange, structure.timestamp));
+
+    log_rejected("two_d_structures", &rejected);
+
+    if valid.is_empty() {
+        return Upsert::new(structures.len(), rejected, Inserted { rows: Vec::new(), retries: 0 }, started);
     }
 
-    let res = perform_insert(pool, || {
+    let (inserted, error) = insert_chunks(target, &TWO_D_STRUCTURE_UPSERT, &valid, |chunk| {
         let mut query_builder: QueryBuilder<Postgres> = QueryBuilder::new("INSERT INTO two_d_structures (symbol, structure, timerange, timestamp, high, low, direction)");
-        query_builder.push_values(structures.iter(), |mut b, structure| {
+        query_builder.push_values(chunk, |mut b, structure| {
             b.push_bind(&structure.symbol)
              .push_bind(&structure.structure)
              .push_bind(&structure.timerange)
@@ -254,17 +678,21 @@ pub async fn insert_two_d_structures(pool: Arc<PgPool>, structures: &[TwoDStruct
              .push_bind(&structure.low)
              .push_bind(&structure.direction);
         });
+        push_upsert(&mut query_builder, &TWO_D_STRUCTURE_UPSERT, mode, "symbol, structure, timerange, timestamp, high, low, direction");
 
         query_builder
     }).await;
 
-    if let Err(e) = res {
-        LogFile::add_log(LogLevel::Error, &format!("Failed to insert two_d_structures: {}", e)).ok();
+    match error {
+        None => {
+            LogFile::add_log(LogLevel::Info, "TwoD structures inserted successfully").ok();
 
-        Err(Error::from(format!("Failed to insert two_d_structures: {}", e)))
-    } else {
-        LogFile::add_log(LogLevel::Info, "TwoD structures inserted successfully").ok();
+            Upsert::new(structures.len(), rejected, inserted, started)
+        }
+        Some(e) => {
+            LogFile::add_log(LogLevel::Error, &format!("Failed to insert two_d_structures: {}", e)).ok();
 
-        Ok(())
+            Upsert::failed(structures.len(), rejected, inserted, e, started)
+        }
     }
 }
\ No newline at end of file
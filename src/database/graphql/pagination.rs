@@ -0,0 +1,234 @@
+use crate::{Candle, OneDStructures, Session, Trend, TwoDStructures};
+
+use async_graphql::{
+    connection::{Connection, Edge, OpaqueCursor},
+    OutputType
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+// Page size when neither first nor last is given
+pub const DEFAULT_PAGE_SIZE: usize = 100;
+pub const MAX_PAGE_SIZE: usize = 1000;
+
+pub type EntityConnection<T> = Connection<OpaqueCursor<EntityCursor>, T>;
+
+// Position of a row in the newest first order
+// Rows inserted meanwhile don't move it, so pages stay stable
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct EntityCursor {
+    pub timestamp: DateTime<Utc>,
+    pub symbol: String,
+    // The label for sessions
+    pub timerange: String,
+    // Orders the structures sharing a timestamp
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub structure: String,
+}
+
+// The page asked for, resolved from the Relay arguments
+pub struct PageRequest {
+    pub after: Option<EntityCursor>,
+    pub before: Option<EntityCursor>,
+    pub size: usize,
+    // Only last was given, the page ends at before
+    pub backward: bool,
+}
+
+// Keyset of a table, the same columns as in the cursor
+pub struct PageTable {
+    pub name: &'static str,
+    pub columns: &'static str,
+    pub keys: &'static [&'static str],
+}
+
+pub const CANDLE_PAGES: PageTable = PageTable {
+    name: "candles",
+    columns: "symbol, timerange, timestamp, open, high, low, close, volume, direction",
+    keys: &["timestamp", "symbol", "timerange"],
+};
+
+pub const SESSION_PAGES: PageTable = PageTable {
+    name: "sessions",
+    columns: "symbol, label, start_time, end_time, high, low, open, close, volume",
+    keys: &["start_time", "symbol", "label"],
+};
+
+pub const TREND_PAGES: PageTable = PageTable {
+    name: "trends",
+    columns: "symbol, timerange, start_time, end_time, direction, high, low",
+    keys: &["start_time", "symbol", "timerange"],
+};
+
+pub const ONE_D_STRUCTURE_PAGES: PageTable = PageTable {
+    name: "one_d_structures",
+    columns: "symbol, structure, timerange, timestamp, price, direction",
+    keys: &["timestamp", "symbol", "timerange", "structure"],
+};
+
+pub const TWO_D_STRUCTURE_PAGES: PageTable = PageTable {
+    name: "two_d_structures",
+    columns: "symbol, structure, timerange, timestamp, high, low, direction",
+    keys: &["timestamp", "symbol", "timerange", "structure"],
+};
+
+impl PageRequest {
+    // A page is read in one direction, so first and last can't be given together
+    pub fn new(after: Option<OpaqueCursor<EntityCursor>>, before: Option<OpaqueCursor<EntityCursor>>, first: Option<usize>, last: Option<usize>) -> Result<Self, String> {
+        if first.is_some() && last.is_some() {
+            return Err("first and last can't be given together".to_string());
+        }
+
+        let backward = last.is_some();
+        let size = if backward { last } else { first };
+
+        Ok(PageRequest {
+            after: after.map(|cursor| cursor.0),
+            before: before.map(|cursor| cursor.0),
+            size: size.unwrap_or(DEFAULT_PAGE_SIZE).min(MAX_PAGE_SIZE),
+            backward,
+        })
+    }
+}
+
+pub fn into_connection<T: OutputType>(mut rows: Vec<T>, request: &PageRequest, cursor: fn(&T) -> EntityCursor) -> EntityConnection<T> {
+    let has_more = rows.len() > request.size;
+    rows.truncate(request.size);
+
+    let (has_previous_page, has_next_page) = if request.backward {
+        rows.reverse();
+
+        (has_more, request.before.is_some())
+    } else {
+        (request.after.is_some(), has_more)
+    };
+
+    let mut connection = Connection::new(has_previous_page, has_next_page);
+    connection.edges.extend(rows.into_iter().map(|row| Edge::new(OpaqueCursor(cursor(&row)), row)));
+
+    connection
+}
+
+pub fn candle_cursor(candle: &Candle) -> EntityCursor {
+    EntityCursor {
+        timestamp: candle.timestamp,
+        symbol: candle.symbol.clone(),
+        timerange: candle.timerange.clone(),
+        structure: String::new(),
+    }
+}
+
+pub fn session_cursor(session: &Session) -> EntityCursor {
+    EntityCursor {
+        timestamp: session.start_time,
+        symbol: session.symbol.clone(),
+        timerange: session.label.clone(),
+        structure: String::new(),
+    }
+}
+
+pub fn trend_cursor(trend: &Trend) -> EntityCursor {
+    EntityCursor {
+        timestamp: trend.start_time,
+        symbol: trend.symbol.clone(),
+        timerange: trend.timerange.clone(),
+        structure: String::new(),
+    }
+}
+
+pub fn one_d_structure_cursor(structure: &OneDStructures) -> EntityCursor {
+    EntityCursor {
+        timestamp: structure.timestamp,
+        symbol: structure.symbol.clone(),
+        timerange: structure.timerange.clone(),
+        structure: structure.structure.clone(),
+    }
+}
+
+pub fn two_d_structure_cursor(structure: &TwoDStructures) -> EntityCursor {
+    EntityCursor {
+        timestamp: structure.timestamp,
+        symbol: structure.symbol.clone(),
+        timerange: structure.timerange.clone(),
+        structure: structure.structure.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{into_connection, EntityCursor, PageRequest, DEFAULT_PAGE_SIZE, MAX_PAGE_SIZE};
+
+    use async_graphql::connection::{CursorType, OpaqueCursor};
+    use chrono::{TimeZone, Utc};
+
+    fn cursor(minute: i32) -> EntityCursor {
+        EntityCursor {
+            timestamp: Utc.with_ymd_and_hms(2024, 1, 1, 8, minute as u32, 0).unwrap(),
+            symbol: "BTC".to_string(),
+            timerange: "1m".to_string(),
+            structure: String::new(),
+        }
+    }
+
+    // The rows of the tests are their minute
+    fn row_cursor(minute: &i32) -> EntityCursor {
+        cursor(*minute)
+    }
+
+    #[test]
+    fn cursors_round_trip() {
+        for structure in ["", "BOS"] {
+            let cursor = EntityCursor { structure: structure.to_string(), ..cursor(5) };
+            let encoded = OpaqueCursor(cursor.clone()).encode_cursor();
+            let decoded = OpaqueCursor::<EntityCursor>::decode_cursor(&encoded).unwrap().0;
+
+            assert_eq!(decoded.timestamp, cursor.timestamp);
+            assert_eq!(decoded.symbol, cursor.symbol);
+            assert_eq!(decoded.timerange, cursor.timerange);
+            assert_eq!(decoded.structure, cursor.structure);
+        }
+
+        assert!(OpaqueCursor::<EntityCursor>::decode_cursor("not a cursor").is_err());
+    }
+
+    #[test]
+    fn rejects_first_and_last_together() {
+        assert!(PageRequest::new(None, None, Some(10), Some(10)).is_err());
+    }
+
+    #[test]
+    fn sizes_the_page() {
+        let default = PageRequest::new(None, None, None, None).unwrap();
+        assert_eq!((default.size, default.backward), (DEFAULT_PAGE_SIZE, false));
+
+        let capped = PageRequest::new(None, None, Some(MAX_PAGE_SIZE + 1), None).unwrap();
+        assert_eq!(capped.size, MAX_PAGE_SIZE);
+
+        let backward = PageRequest::new(None, None, None, Some(5)).unwrap();
+        assert_eq!((backward.size, backward.backward), (5, true));
+    }
+
+    #[test]
+    fn forward_pages_continue_after_the_cursor() {
+        let request = PageRequest::new(Some(OpaqueCursor(cursor(9))), None, Some(2), None).unwrap();
+
+        // One row more than the page was read, so there is a next page
+        let connection = into_connection(vec![8, 7, 6], &request, row_cursor);
+
+        assert_eq!(connection.edges.iter().map(|edge| edge.node).collect::<Vec<_>>(), [8, 7]);
+        assert!(connection.has_previous_page);
+        assert!(connection.has_next_page);
+    }
+
+    #[test]
+    fn backward_pages_end_at_the_cursor() {
+        let request = PageRequest::new(None, Some(OpaqueCursor(cursor(5))), None, Some(2)).unwrap();
+
+        // Rows are read away from the cursor, the page is reversed back to newest first
+        let connection = into_connection(vec![6, 7], &request, row_cursor);
+
+        assert_eq!(connection.edges.iter().map(|edge| edge.node).collect::<Vec<_>>(), [7, 6]);
+        assert!(!connection.has_previous_page);
+        assert!(connection.has_next_page);
+    }
+}
@@ -1,12 +1,43 @@
-use crate::{database::structures::PermissionLevel, Candle, OneDStructures, Session, Trend, TwoDStructures};
+use crate::{
+    database::{
+        graphql::{
+            aggregation::{aggregate_candles, resolve_bucket, resolve_session},
+            filters::{structure_exists, symbol_exists, Direction, SortOrder, TimeBounds, TimerangeLabel},
+            pagination::{
+                candle_cursor,
+                into_connection,
+                one_d_structure_cursor,
+                session_cursor,
+                trend_cursor,
+                two_d_structure_cursor,
+                EntityConnection,
+                PageRequest,
+                PageTable,
+                CANDLE_PAGES,
+                ONE_D_STRUCTURE_PAGES,
+                SESSION_PAGES,
+                TREND_PAGES,
+                TWO_D_STRUCTURE_PAGES
+            },
+            selection::{page_query, select_rows, Selection}
+        },
+        structures::PermissionLevel
+    },
+    Candle,
+    OneDStructures,
+    Session,
+    Trend,
+    TwoDStructures
+};
 
-use async_graphql::{Context, Error, Interface, Object, SimpleObject};
+use async_graphql::{connection::query, Context, Error, ErrorExtensions, Interface, Object};
+use chrono::{DateTime, Utc};
 use common::utils::log::{
     LogFile, LogLevel,
 };
-use sqlx::{PgPool, query_as};
+use sqlx::{FromRow, PgPool, postgres::PgRow};
 use std::sync::Arc;
-use tokio::try_join;
+use tracing::instrument;
 
 // GraphQL interface for entities
 // So we can facilitate polymorphic queries
@@ -26,261 +57,382 @@ pub enum CommonFields {
 
 // This struct is used to return all common fields in a single query
 // It allows us to return different types of entities
-#[derive(SimpleObject)]
+// It only holds the filters, so each field is fetched when it is selected
+// The fields are nullable, so a failing entity doesn't hide the others
 pub struct AllCommonFieldsResult {
-    pub candles: Vec<Candle>,
-    pub one_d_structures: Vec<OneDStructures>,
-    pub trends: Vec<Trend>,
-    pub two_d_structures: Vec<TwoDStructures>,
-    pub sessions: Vec<Session>,
+    // Sessions ignore the timerange, the direction and the structure
+    pub selection: Selection,
 }
 
+#[Object]
+impl AllCommonFieldsResult {
+    pub async fn candles(&self, ctx: &Context<'_>) -> Option<Vec<Candle>> {
+        let selection = Selection { structure: None, ..self.selection.clone() };
+
+        partial(ctx, fetch_entities(ctx, "candles", &CANDLE_PAGES, selection).await)
+    }
+
+    pub async fn one_d_structures(&self, ctx: &Context<'_>) -> Option<Vec<OneDStructures>> {
+        partial(ctx, fetch_entities(ctx, "oneDStructures", &ONE_D_STRUCTURE_PAGES, self.selection.clone()).await)
+    }
+
+    pub async fn trends(&self, ctx: &Context<'_>) -> Option<Vec<Trend>> {
+        let selection = Selection { structure: None, ..self.selection.clone() };
+
+        partial(ctx, fetch_entities(ctx, "trends", &TREND_PAGES, selection).await)
+    }
+
+    pub async fn two_d_structures(&self, ctx: &Context<'_>) -> Option<Vec<TwoDStructures>> {
+        partial(ctx, fetch_entities(ctx, "twoDStructures", &TWO_D_STRUCTURE_PAGES, self.selection.clone()).await)
+    }
+
+    pub async fn sessions(&self, ctx: &Context<'_>) -> Option<Vec<Session>> {
+        let selection = Selection { timerange: None, direction: None, structure: None, ..self.selection.clone() };
+
+        partial(ctx, fetch_entities(ctx, "sessions", &SESSION_PAGES, selection).await)
+    }
+}
+
+// Rows returned when no limit is given
+const DEFAULT_LIMIT: i64 = 100;
+
+// Codes set in the extensions of the errors
+const FORBIDDEN: &str = "FORBIDDEN";
+const DATABASE_ERROR: &str = "DATABASE_ERROR";
+const INTERNAL_ERROR: &str = "INTERNAL_ERROR";
+const BAD_USER_INPUT: &str = "BAD_USER_INPUT";
+
 // Main GraphQL query root
 pub struct QueryRoot;
 
 #[Object]
 impl QueryRoot {
-    pub async fn get(&self, ctx: &Context<'_>, symbol: String, timerange: String, min_timestamp: Option<i64>, max_timestamp: Option<i64>, limit: Option<i64>) -> Result<AllCommonFieldsResult, Error> {
+    // Every entity of a symbol at once
+    // The selected fields are resolved concurrently
+    // The bounds include minTimestamp and exclude maxTimestamp unless told otherwise
+    #[allow(clippy::too_many_arguments)]
+    pub async fn get(&self, ctx: &Context<'_>, symbol: String, timerange: TimerangeLabel, min_timestamp: Option<DateTime<Utc>>, max_timestamp: Option<DateTime<Utc>>, #[graphql(default = true)] include_min: bool, #[graphql(default)] include_max: bool, direction: Option<Direction>, structure: Option<String>, #[graphql(default)] order: SortOrder, limit: Option<i64>) -> Result<AllCommonFieldsResult, Error> {
         // Check user permissions
         // But in this case, we allow everyone to access this query
-        let permission = ctx.data::<PermissionLevel>()?;
-        if *permission != PermissionLevel::Admin && *permission != PermissionLevel::User {
-            return Err(Error::from("Permission denied"));
-        }
+        require_access(ctx)?;
+
+        let bounds = TimeBounds::new(min_timestamp, max_timestamp, include_min, include_max)
+            .map_err(|e| Error::new(e).extend_with(|_, extensions| extensions.set("code", BAD_USER_INPUT)))?;
+
+        let selection = Selection {
+            symbol,
+            timerange: Some(timerange.as_str().to_string()),
+            bounds,
+            direction: direction.map(|direction| direction.as_str()),
+            structure,
+            order,
+            limit: limit.unwrap_or(DEFAULT_LIMIT),
+        };
 
-        let pool = ctx.data::<PgPool>()?;
-        let pool = Arc::new(pool.clone());
+        // Checked once here, the fields only run with a known symbol and structure
+        let pool = entity_pool(ctx, "get")?;
+        require_selection(&pool, "get", &selection).await?;
 
-        let sybmol = Arc::new(symbol);
-        let timerange = Arc::new(timerange);
-        let min_timestamp = Arc::new(min_timestamp);
-        let max_timestamp = Arc::new(max_timestamp);
-        let limit = Arc::new(limit.unwrap_or(100));
+        Ok(AllCommonFieldsResult { selection })
+    }
 
-        let candle_selection = tokio::spawn({
-            let pool = Arc::clone(&pool);
+    #[allow(clippy::too_many_arguments)]
+    pub async fn candles(&self, ctx: &Context<'_>, symbol: String, timerange: TimerangeLabel, min_timestamp: Option<DateTime<Utc>>, max_timestamp: Option<DateTime<Utc>>, #[graphql(default = true)] include_min: bool, #[graphql(default)] include_max: bool, direction: Option<Direction>, #[graphql(default)] order: SortOrder, limit: Option<i64>) -> Result<Vec<Candle>, Error> {
+        let selection = Selection {
+            symbol,
+            timerange: Some(timerange.as_str().to_string()),
+            bounds: entity_bounds("candles", min_timestamp, max_timestamp, include_min, include_max)?,
+            direction: direction.map(|direction| direction.as_str()),
+            structure: None,
+            order,
+            limit: limit.unwrap_or(DEFAULT_LIMIT),
+        };
 
-            let symbol = Arc::clone(&sybmol);
-            let timerange = Arc::clone(&timerange);
-            let min_timestamp = Arc::clone(&min_timestamp);
-            let max_timestamp = Arc::clone(&max_timestamp);
-            let limit = Arc::clone(&limit);
+        let pool = entity_pool(ctx, "candles")?;
+        require_selection(&pool, "candles", &selection).await?;
 
-            async move {
-                let res = select_candles(pool, symbol, timerange.into(), min_timestamp, max_timestamp, limit).await;
-
-                if let Err(ref e) = res {
-                    LogFile::add_log(LogLevel::Error, &format!("Failed to retrieve candles: {}", e)).ok();
-                }
-
-                res
-            }
-        });
-
-        let session_selection = tokio::spawn({
-            let pool = Arc::clone(&pool);
-
-            let symbol = Arc::clone(&sybmol);
-            let min_timestamp = Arc::clone(&min_timestamp);
-            let max_timestamp = Arc::clone(&max_timestamp);
-            let limit = Arc::clone(&limit);
-
-            async move {
-                let res = select_sessions(pool, symbol, min_timestamp, max_timestamp, limit).await;
-
-                if let Err(ref e) = res {
-                    LogFile::add_log(LogLevel::Error, &format!("Failed to retrieve sessions: {}", e)).ok();
-                }
-
-                res
-            }
-        });
-
-        let trend_selection = tokio::spawn({
-            let pool = Arc::clone(&pool);
-
-            let symbol = Arc::clone(&sybmol);
-            let timerange = Arc::clone(&timerange);
-            let min_timestamp = Arc::clone(&min_timestamp);
-            let max_timestamp = Arc::clone(&max_timestamp);
-            let limit = Arc::clone(&limit);
-
-            async move {
-                let res = select_trends(pool, symbol, timerange, min_timestamp, max_timestamp, limit).await;
-
-                if let Err(ref e) = res {
-                    LogFile::add_log(LogLevel::Error, &format!("Failed to retrieve trends: {}", e)).ok();
-                }
-
-                res
-            }
-        });
-
-        let twodstructure_selection = tokio::spawn({
-            let pool = Arc::clone(&pool);
-
-            let symbol = Arc::clone(&sybmol);
-            let timerange = Arc::clone(&timerange);
-            let min_timestamp = Arc::clone(&min_timestamp);
-            let max_timestamp = Arc::clone(&max_timestamp);
-            let limit = Arc::clone(&limit);
-
-            async move {
-                let res = select_two_d_structures(pool, symbol, timerange, min_timestamp, max_timestamp, limit).await;
-
-                if let Err(ref e) = res {
-                    LogFile::add_log(LogLevel::Error, &format!("Failed to retrieve 2D structures: {}", e)).ok();
-                }
-
-                res
-            }
-        });
-
-        let onedstructure_selection = tokio::spawn({
-            let pool = Arc::clone(&pool);
-
-            let symbol = Arc::clone(&sybmol);
-            let timerange = Arc::clone(&timerange);
-            let min_timestamp = Arc::clone(&min_timestamp);
-            let max_timestamp = Arc::clone(&max_timestamp);
-            let limit = Arc::clone(&limit);
-
-            async move {
-                let res = select_one_d_structures(pool, symbol, timerange, min_timestamp, max_timestamp, limit).await;
-
-                if let Err(ref e) = res {
-                    LogFile::add_log(LogLevel::Error, &format!("Failed to retrieve 1D structures: {}", e)).ok();
-                }
-
-                res
-            }
-        });
-
-        // Spawn all tasks simultaneously and wait for all to complete
-        // Even if the pool as a size of 5, we can still win time
-        let (candles, sessions, trends, two_d_structures, one_d_structures) = try_join!(
-            candle_selection,
-            session_selection,
-            trend_selection,
-            twodstructure_selection,
-            onedstructure_selection
-        )?;
-
-        let mut fail = false;
-        if candles.is_err() || sessions.is_err() || trends.is_err() || two_d_structures.is_err() || one_d_structures.is_err() {
-            fail = true;
-        }
+        fetch_entities(ctx, "candles", &CANDLE_PAGES, selection).await
+    }
 
-        let all = AllCommonFieldsResult {
-            candles: candles?,
-            one_d_structures: one_d_structures?,
-            trends: trends?,
-            two_d_structures: two_d_structures?,
-            sessions: sessions?,
+    #[allow(clippy::too_many_arguments)]
+    pub async fn sessions(&self, ctx: &Context<'_>, symbol: String, min_timestamp: Option<DateTime<Utc>>, max_timestamp: Option<DateTime<Utc>>, #[graphql(default = true)] include_min: bool, #[graphql(default)] include_max: bool, #[graphql(default)] order: SortOrder, limit: Option<i64>) -> Result<Vec<Session>, Error> {
+        let selection = Selection {
+            symbol,
+            timerange: None,
+            bounds: entity_bounds("sessions", min_timestamp, max_timestamp, include_min, include_max)?,
+            direction: None,
+            structure: None,
+            order,
+            limit: limit.unwrap_or(DEFAULT_LIMIT),
         };
 
-        if !fail {
-            LogFile::add_log(LogLevel::Info, "All data retrieved successfully").map_err(|e| Error::from(format!("Failed to log: {}", e)))?;
-        }
+        let pool = entity_pool(ctx, "sessions")?;
+        require_selection(&pool, "sessions", &selection).await?;
 
-        Ok(all)
+        fetch_entities(ctx, "sessions", &SESSION_PAGES, selection).await
     }
+
+    #[allow(clippy::too_many_arguments)]
+    pub async fn trends(&self, ctx: &Context<'_>, symbol: String, timerange: TimerangeLabel, min_timestamp: Option<DateTime<Utc>>, max_timestamp: Option<DateTime<Utc>>, #[graphql(default = true)] include_min: bool, #[graphql(default)] include_max: bool, direction: Option<Direction>, #[graphql(default)] order: SortOrder, limit: Option<i64>) -> Result<Vec<Trend>, Error> {
+        let selection = Selection {
+            symbol,
+            timerange: Some(timerange.as_str().to_string()),
+            bounds: entity_bounds("trends", min_timestamp, max_timestamp, include_min, include_max)?,
+            direction: direction.map(|direction| direction.as_str()),
+            structure: None,
+            order,
+            limit: limit.unwrap_or(DEFAULT_LIMIT),
+        };
+
+        let pool = entity_pool(ctx, "trends")?;
+        require_selection(&pool, "trends", &selection).await?;
+
+        fetch_entities(ctx, "trends", &TREND_PAGES, selection).await
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub async fn one_d_structures(&self, ctx: &Context<'_>, symbol: String, timerange: TimerangeLabel, min_timestamp: Option<DateTime<Utc>>, max_timestamp: Option<DateTime<Utc>>, #[graphql(default = true)] include_min: bool, #[graphql(default)] include_max: bool, direction: Option<Direction>, structure: Option<String>, #[graphql(default)] order: SortOrder, limit: Option<i64>) -> Result<Vec<OneDStructures>, Error> {
+        let selection = Selection {
+            symbol,
+            timerange: Some(timerange.as_str().to_string()),
+            bounds: entity_bounds("oneDStructures", min_timestamp, max_timestamp, include_min, include_max)?,
+            direction: direction.map(|direction| direction.as_str()),
+            structure,
+            order,
+            limit: limit.unwrap_or(DEFAULT_LIMIT),
+        };
+
+        let pool = entity_pool(ctx, "oneDStructures")?;
+        require_selection(&pool, "oneDStructures", &selection).await?;
+
+        fetch_entities(ctx, "oneDStructures", &ONE_D_STRUCTURE_PAGES, selection).await
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub async fn two_d_structures(&self, ctx: &Context<'_>, symbol: String, timerange: TimerangeLabel, min_timestamp: Option<DateTime<Utc>>, max_timestamp: Option<DateTime<Utc>>, #[graphql(default = true)] include_min: bool, #[graphql(default)] include_max: bool, direction: Option<Direction>, structure: Option<String>, #[graphql(default)] order: SortOrder, limit: Option<i64>) -> Result<Vec<TwoDStructures>, Error> {
+        let selection = Selection {
+            symbol,
+            timerange: Some(timerange.as_str().to_string()),
+            bounds: entity_bounds("twoDStructures", min_timestamp, max_timestamp, include_min, include_max)?,
+            direction: direction.map(|direction| direction.as_str()),
+            structure,
+            order,
+            limit: limit.unwrap_or(DEFAULT_LIMIT),
+        };
+
+        let pool = entity_pool(ctx, "twoDStructures")?;
+        require_selection(&pool, "twoDStructures", &selection).await?;
+
+        fetch_entities(ctx, "twoDStructures", &TWO_D_STRUCTURE_PAGES, selection).await
+    }
+
+    // Candles of a larger timerange built from the stored ones, newest first
+    // The target is a timerange of TIMERANGES or a bucket size in seconds
+    // With a session, the buckets start at its open and only cover its hours
+    // Buckets missing candles at either end are left out unless includePartial is set
+    #[allow(clippy::too_many_arguments)]
+    pub async fn aggregated_candles(&self, ctx: &Context<'_>, symbol: String, source_timerange: TimerangeLabel, timerange: Option<TimerangeLabel>, bucket_seconds: Option<i64>, session: Option<String>, min_timestamp: Option<DateTime<Utc>>, max_timestamp: Option<DateTime<Utc>>, #[graphql(default = true)] include_min: bool, #[graphql(default)] include_max: bool, #[graphql(default)] include_partial: bool, limit: Option<i64>) -> Result<Vec<Candle>, Error> {
+        let pool = entity_pool(ctx, "aggregatedCandles")?;
+        let bounds = entity_bounds("aggregatedCandles", min_timestamp, max_timestamp, include_min, include_max)?;
+
+        let limit = limit.unwrap_or(DEFAULT_LIMIT);
+        let bucket = resolve_bucket(source_timerange.as_str(), timerange.map(|timerange| timerange.as_str()), bucket_seconds, limit)
+            .map_err(|e| entity_error("aggregatedCandles", BAD_USER_INPUT, e))?;
+
+        let session = session.as_deref()
+            .map(resolve_session)
+            .transpose()
+            .map_err(|e| entity_error("aggregatedCandles", BAD_USER_INPUT, e))?;
+
+        require_symbol(&pool, "aggregatedCandles", &symbol).await?;
+
+        let res = aggregate_candles(&pool, &symbol, source_timerange.as_str(), &bucket, session.as_ref(), &bounds, limit, include_partial).await;
+
+        res.map_err(|e| {
+            LogFile::add_log(LogLevel::Error, &format!("Failed to aggregate candles: {}", e)).ok();
+            entity_error("aggregatedCandles", DATABASE_ERROR, format!("Failed to aggregate candles: {}", e))
+        })
+    }
+
+    // Relay connections, newest first
+    // Page forward with first/after and backward with last/before
+    #[allow(clippy::too_many_arguments)]
+    pub async fn candles_connection(&self, ctx: &Context<'_>, symbol: String, timerange: TimerangeLabel, after: Option<String>, before: Option<String>, first: Option<i32>, last: Option<i32>) -> Result<EntityConnection<Candle>, Error> {
+        let pool = entity_pool(ctx, "candles")?;
+        require_symbol(&pool, "candles", &symbol).await?;
+
+        query(after, before, first, last, |after, before, first, last| async move {
+            let request = PageRequest::new(after, before, first, last)
+                .map_err(|e| entity_error("candles", BAD_USER_INPUT, e))?;
+            let rows = fetch_page(&pool, &CANDLE_PAGES, &[("symbol", &symbol), ("timerange", timerange.as_str())], &request).await?;
+
+            Ok::<_, Error>(into_connection(rows, &request, candle_cursor))
+        }).await
+    }
+
+    pub async fn sessions_connection(&self, ctx: &Context<'_>, symbol: String, after: Option<String>, before: Option<String>, first: Option<i32>, last: Option<i32>) -> Result<EntityConnection<Session>, Error> {
+        let pool = entity_pool(ctx, "sessions")?;
+        require_symbol(&pool, "sessions", &symbol).await?;
+
+        query(after, before, first, last, |after, before, first, last| async move {
+            let request = PageRequest::new(after, before, first, last)
+                .map_err(|e| entity_error("sessions", BAD_USER_INPUT, e))?;
+            let rows = fetch_page(&pool, &SESSION_PAGES, &[("symbol", &symbol)], &request).await?;
+
+            Ok::<_, Error>(into_connection(rows, &request, session_cursor))
+        }).await
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub async fn trends_connection(&self, ctx: &Context<'_>, symbol: String, timerange: TimerangeLabel, after: Option<String>, before: Option<String>, first: Option<i32>, last: Option<i32>) -> Result<EntityConnection<Trend>, Error> {
+        let pool = entity_pool(ctx, "trends")?;
+        require_symbol(&pool, "trends", &symbol).await?;
+
+        query(after, before, first, last, |after, before, first, last| async move {
+            let request = PageRequest::new(after, before, first, last)
+                .map_err(|e| entity_error("trends", BAD_USER_INPUT, e))?;
+            let rows = fetch_page(&pool, &TREND_PAGES, &[("symbol", &symbol), ("timerange", timerange.as_str())], &request).await?;
+
+            Ok::<_, Error>(into_connection(rows, &request, trend_cursor))
+        }).await
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub async fn one_d_structures_connection(&self, ctx: &Context<'_>, symbol: String, timerange: TimerangeLabel, after: Option<String>, before: Option<String>, first: Option<i32>, last: Option<i32>) -> Result<EntityConnection<OneDStructures>, Error> {
+        let pool = entity_pool(ctx, "oneDStructures")?;
+        require_symbol(&pool, "oneDStructures", &symbol).await?;
+
+        query(after, before, first, last, |after, before, first, last| async move {
+            let request = PageRequest::new(after, before, first, last)
+                .map_err(|e| entity_error("oneDStructures", BAD_USER_INPUT, e))?;
+            let rows = fetch_page(&pool, &ONE_D_STRUCTURE_PAGES, &[("symbol", &symbol), ("timerange", timerange.as_str())], &request).await?;
+
+            Ok::<_, Error>(into_connection(rows, &request, one_d_structure_cursor))
+        }).await
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub async fn two_d_structures_connection(&self, ctx: &Context<'_>, symbol: String, timerange: TimerangeLabel, after: Option<String>, before: Option<String>, first: Option<i32>, last: Option<i32>) -> Result<EntityConnection<TwoDStructures>, Error> {
+        let pool = entity_pool(ctx, "twoDStructures")?;
+        require_symbol(&pool, "twoDStructures", &symbol).await?;
+
+        query(after, before, first, last, |after, before, first, last| async move {
+            let request = PageRequest::new(after, before, first, last)
+                .map_err(|e| entity_error("twoDStructures", BAD_USER_INPUT, e))?;
+            let rows = fetch_page(&pool, &TWO_D_STRUCTURE_PAGES, &[("symbol", &symbol), ("timerange", timerange.as_str())], &request).await?;
+
+            Ok::<_, Error>(into_connection(rows, &request, two_d_structure_cursor))
+        }).await
+    }
+}
+
+// Everyone with a valid token can read the data
+fn require_access(ctx: &Context<'_>) -> Result<(), Error> {
+    let permission = ctx.data::<PermissionLevel>()?;
+    if *permission != PermissionLevel::Admin && *permission != PermissionLevel::User {
+        return Err(Error::new("Permission denied").extend_with(|_, extensions| extensions.set("code", FORBIDDEN)));
+    }
+
+    Ok(())
+}
+
+// Every error of an entity field says which entity failed
+// So a client can tell what is missing from a partial result
+fn entity_error(entity: &str, code: &str, message: String) -> Error {
+    Error::new(message).extend_with(|_, extensions| {
+        extensions.set("entity", entity);
+        extensions.set("code", code);
+    })
 }
 
-pub async fn select_candles(pool: Arc<PgPool>, symbol: Arc<String>, timerange: Arc<String>, min_timestamp: Arc<Option<i64>>, max_timestamp: Arc<Option<i64>>, limit: Arc<i64>) -> Result<Vec<Candle>, sqlx::Error> {
-    query_as::<_, Candle>(r#"
-        SELECT symbol, timerange, timestamp, open, high, low, close, volume, direction
-        FROM candles
-        WHERE symbol = $1
-            AND ($2 IS NULL OR timerange = $2)
-            AND ($4 IS NULL OR (EXTRACT(EPOCH FROM timestamp) > $4))
-            AND ($5 IS NULL OR EXTRACT(EPOCH FROM timestamp) < $5)
-        ORDER BY timestamp DESC
-        LIMIT $3
-    "#)
-    .bind(symbol.as_ref())
-    .bind(timerange.as_ref())
-    .bind(limit.as_ref())
-    .bind(min_timestamp.as_ref())
-    .bind(max_timestamp.as_ref())
-    .fetch_all(pool.as_ref())
-    .await
+// An error goes up to the parent object, even when the field is nullable
+// So it is reported here instead and only this field is set to null
+fn partial<T>(ctx: &Context<'_>, result: Result<T, Error>) -> Option<T> {
+    match result {
+        Ok(rows) => Some(rows),
+        Err(e) => {
+            ctx.add_error(ctx.set_error_path(e.into_server_error(ctx.item.pos)));
+            None
+        }
+    }
+}
+
+fn entity_pool(ctx: &Context<'_>, entity: &str) -> Result<Arc<PgPool>, Error> {
+    require_access(ctx)
+        .map_err(|e| e.extend_with(|_, extensions| extensions.set("entity", entity)))?;
+
+    let pool = ctx.data::<PgPool>()
+        .map_err(|e| entity_error(entity, INTERNAL_ERROR, e.message))?;
+
+    Ok(Arc::new(pool.clone()))
+}
+
+fn entity_bounds(entity: &str, min_timestamp: Option<DateTime<Utc>>, max_timestamp: Option<DateTime<Utc>>, include_min: bool, include_max: bool) -> Result<TimeBounds, Error> {
+    TimeBounds::new(min_timestamp, max_timestamp, include_min, include_max)
+        .map_err(|e| entity_error(entity, BAD_USER_INPUT, e))
+}
+
+// An unknown symbol is an error rather than an empty result
+// So a typo doesn't look like missing data
+async fn require_symbol(pool: &PgPool, entity: &str, symbol: &str) -> Result<(), Error> {
+    match symbol_exists(pool, symbol).await {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(entity_error(entity, BAD_USER_INPUT, format!("Unknown symbol {}", symbol))),
+        Err(e) => {
+            LogFile::add_log(LogLevel::Error, &format!("Failed to check symbol {}: {}", symbol, e)).ok();
+
+            Err(entity_error(entity, DATABASE_ERROR, format!("Failed to check symbol {}: {}", symbol, e)))
+        }
+    }
 }
 
-pub async fn select_sessions(pool: Arc<PgPool>, symbol: Arc<String>, min_timestamp: Arc<Option<i64>>, max_timestamp: Arc<Option<i64>>, limit: Arc<i64>) -> Result<Vec<Session>, sqlx::Error> {
-    query_as::<_, Session>(r#"
-        SELECT symbol, label, start_time, end_time, high, low, open, close, volume
-        FROM sessions
-        WHERE symbol = $1
-            AND ($2 IS NULL OR (EXTRACT(EPOCH FROM start_time) > $2))
-            AND ($3 IS NULL OR EXTRACT(EPOCH FROM end_time) < $3)
-        ORDER BY start_time DESC
-        LIMIT $4
-    "#)
-    .bind(symbol.as_ref())
-    .bind(min_timestamp.as_ref())
-    .bind(max_timestamp.as_ref())
-    .bind(limit.as_ref())
-    .fetch_all(pool.as_ref())
-    .await
+// An unknown structure is rejected for the same reason
+async fn require_structure(pool: &PgPool, entity: &str, structure: &str) -> Result<(), Error> {
+    match structure_exists(pool, structure).await {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(entity_error(entity, BAD_USER_INPUT, format!("Unknown structure {}", structure))),
+        Err(e) => {
+            LogFile::add_log(LogLevel::Error, &format!("Failed to check structure {}: {}", structure, e)).ok();
+
+            Err(entity_error(entity, DATABASE_ERROR, format!("Failed to check structure {}: {}", structure, e)))
+        }
+    }
 }
 
-pub async fn select_trends(pool: Arc<PgPool>, symbol: Arc<String>, timerange: Arc<String>, min_timestamp: Arc<Option<i64>>, max_timestamp: Arc<Option<i64>>, limit: Arc<i64>) -> Result<Vec<Trend>, sqlx::Error> {
-    query_as::<_, Trend>(r#"
-        SELECT symbol, timerange, start_time, end_time, direction, high, low
-        FROM trends
-        WHERE symbol = $1
-            AND timerange = $2
-            AND ($3 IS NULL OR (EXTRACT(EPOCH FROM start_time) > $3))
-            AND ($4 IS NULL OR EXTRACT(EPOCH FROM end_time) < $4)
-        ORDER BY start_time DESC
-        LIMIT $5
-    "#)
-    .bind(symbol.as_ref())
-    .bind(timerange.as_ref())
-    .bind(min_timestamp.as_ref())
-    .bind(max_timestamp.as_ref())
-    .bind(limit.as_ref())
-    .fetch_all(pool.as_ref())
-    .await
+async fn require_selection(pool: &PgPool, entity: &str, selection: &Selection) -> Result<(), Error> {
+    require_symbol(pool, entity, &selection.symbol).await?;
+
+    match &selection.structure {
+        Some(structure) => require_structure(pool, entity, structure).await,
+        None => Ok(()),
+    }
 }
 
-pub async fn select_two_d_structures(pool: Arc<PgPool>, symbol: Arc<String>, timerange: Arc<String>, min_timestamp: Arc<Option<i64>>, max_timestamp: Arc<Option<i64>>, limit: Arc<i64>) -> Result<Vec<TwoDStructures>, sqlx::Error> {
-    query_as::<_, TwoDStructures>(r#"
-        SELECT symbol, structure, timerange, timestamp, high, low, direction
-        FROM two_d_structures
-        WHERE symbol = $1
-            AND timerange = $2
-            AND ($3 IS NULL OR (EXTRACT(EPOCH FROM timestamp) > $3))
-            AND ($4 IS NULL OR EXTRACT(EPOCH FROM timestamp) < $4)
-        ORDER BY timestamp DESC
-        LIMIT $5
-    "#)
-    .bind(symbol.as_ref())
-    .bind(timerange.as_ref())
-    .bind(min_timestamp.as_ref())
-    .bind(max_timestamp.as_ref())
-    .bind(limit.as_ref())
-    .fetch_all(pool.as_ref())
-    .await
+// Shared by the entity fields and the fields of get
+// The selection is checked by the caller
+async fn fetch_entities<T>(ctx: &Context<'_>, entity: &str, table: &PageTable, selection: Selection) -> Result<Vec<T>, Error>
+where T: for<'r> FromRow<'r, PgRow> + Send + Unpin {
+    let pool = entity_pool(ctx, entity)?;
+
+    let res = select_rows(&pool, table, &selection).await;
+
+    res.map_err(|e| {
+        LogFile::add_log(LogLevel::Error, &format!("Failed to retrieve {}: {}", table.name, e)).ok();
+        entity_error(entity, DATABASE_ERROR, format!("Failed to retrieve {}: {}", table.name, e))
+    })
 }
 
-pub async fn select_one_d_structures(pool: Arc<PgPool>, symbol: Arc<String>, timerange: Arc<String>, min_timestamp: Arc<Option<i64>>, max_timestamp: Arc<Option<i64>>, limit: Arc<i64>) -> Result<Vec<OneDStructures>, sqlx::Error> {
-    query_as::<_, OneDStructures>(r#"
-        SELECT symbol, structure, timerange, timestamp, price, direction
-        FROM one_d_structures
-        WHERE symbol = $1
-            AND timerange = $2
-            AND ($3 IS NULL OR (EXTRACT(EPOCH FROM timestamp) > $3))
-            AND ($4 IS NULL OR EXTRACT(EPOCH FROM timestamp) < $4)
-        ORDER BY timestamp DESC
-        LIMIT $5
-    "#)
-    .bind(symbol.as_ref())
-    .bind(timerange.as_ref())
-    .bind(min_timestamp.as_ref())
-    .bind(max_timestamp.as_ref())
-    .bind(limit.as_ref())
-    .fetch_all(pool.as_ref())
-    .await
+#[instrument(name = "db.page", skip_all, fields(table = table.name), err(Debug))]
+async fn fetch_page<T>(pool: &PgPool, table: &PageTable, filters: &[(&str, &str)], request: &PageRequest) -> Result<Vec<T>, Error>
+where T: for<'r> FromRow<'r, PgRow> + Send + Unpin {
+    let mut query_builder = page_query(table, filters, request);
+
+    match query_builder.build_query_as::<T>().fetch_all(pool).await {
+        Ok(rows) => Ok(rows),
+        Err(e) => {
+            LogFile::add_log(LogLevel::Error, &format!("Failed to retrieve a page of {}: {}", table.name, e)).ok();
+
+            Err(Error::from(format!("Failed to retrieve {}: {}", table.name, e)))
+        }
+    }
 }
\ No newline at end of file
@@ -0,0 +1,141 @@
+use crate::database::graphql::{
+    filters::{SortOrder, TimeBounds},
+    pagination::{EntityCursor, PageRequest, PageTable}
+};
+
+use sqlx::{
+    FromRow,
+    PgPool,
+    postgres::{PgRow, Postgres},
+    QueryBuilder
+};
+use tracing::instrument;
+
+// Rows of an entity table selected by a query
+// The same semantics for every table, so the entities of a symbol line up
+#[derive(Clone, Debug)]
+pub struct Selection {
+    pub symbol: String,
+    // Required by every table but sessions, which have none
+    pub timerange: Option<String>,
+    // Applied to the time column, the first key of the table
+    pub bounds: TimeBounds,
+    pub direction: Option<&'static str>,
+    pub structure: Option<String>,
+    pub order: SortOrder,
+    pub limit: i64,
+}
+
+impl Selection {
+    // The newest rows of a symbol, without any other filter
+    pub fn latest(symbol: String, timerange: Option<String>, limit: i64) -> Self {
+        Selection {
+            symbol,
+            timerange,
+            bounds: TimeBounds::default(),
+            direction: None,
+            structure: None,
+            order: SortOrder::Desc,
+            limit,
+        }
+    }
+}
+
+// The time column is compared to the bounds as is, never converted
+// So the (symbol, timerange, time) indexes can be used
+pub fn select_query<'a>(table: &PageTable, selection: &'a Selection) -> QueryBuilder<'a, Postgres> {
+    let time_column = table.keys[0];
+
+    let mut query_builder = select_from(table);
+    query_builder.push(" WHERE symbol = ").push_bind(&selection.symbol);
+
+    if table.keys.contains(&"timerange") {
+        query_builder.push(" AND timerange = ").push_bind(&selection.timerange);
+    }
+
+    if let Some(min) = selection.bounds.min {
+        query_builder.push(format!(" AND {} {} ", time_column, selection.bounds.min_operator())).push_bind(min);
+    }
+
+    if let Some(max) = selection.bounds.max {
+        query_builder.push(format!(" AND {} {} ", time_column, selection.bounds.max_operator())).push_bind(max);
+    }
+
+    if let Some(direction) = selection.direction {
+        query_builder.push(" AND direction = ").push_bind(direction);
+    }
+
+    if let Some(structure) = &selection.structure {
+        query_builder.push(" AND structure = ").push_bind(structure);
+    }
+
+    push_order(&mut query_builder, table, selection.order.as_sql(), selection.limit);
+
+    query_builder
+}
+
+// A page of a Relay connection, after or before a cursor
+// Select one row more than the page, so we know whether there is another page
+pub fn page_query<'a>(table: &PageTable, filters: &[(&str, &'a str)], request: &PageRequest) -> QueryBuilder<'a, Postgres> {
+    let mut query_builder = select_from(table);
+    query_builder.push(" WHERE TRUE");
+
+    for (column, value) in filters {
+        query_builder.push(format!(" AND {} = ", column)).push_bind(*value);
+    }
+
+    // Newest first, so the rows after a cursor are the older ones
+    if let Some(after) = &request.after {
+        push_cursor(&mut query_builder, table, after, "<");
+    }
+
+    if let Some(before) = &request.before {
+        push_cursor(&mut query_builder, table, before, ">");
+    }
+
+    // A backward page is read from its end then reversed
+    let order = if request.backward { SortOrder::Asc } else { SortOrder::Desc };
+    push_order(&mut query_builder, table, order.as_sql(), (request.size + 1) as i64);
+
+    query_builder
+}
+
+#[instrument(name = "db.select", skip_all, fields(table = table.name, symbol = %selection.symbol), err)]
+pub async fn select_rows<T>(pool: &PgPool, table: &PageTable, selection: &Selection) -> Result<Vec<T>, sqlx::Error>
+where T: for<'r> FromRow<'r, PgRow> + Send + Unpin {
+    let mut query_builder = select_query(table, selection);
+
+    query_builder.build_query_as::<T>()
+        .fetch_all(pool)
+        .await
+}
+
+fn select_from<'a>(table: &PageTable) -> QueryBuilder<'a, Postgres> {
+    QueryBuilder::new(format!("SELECT {} FROM {}", table.columns, table.name))
+}
+
+// Every key is ordered, so rows sharing a time keep a stable order
+fn push_order(query_builder: &mut QueryBuilder<Postgres>, table: &PageTable, order: &str, limit: i64) {
+    let keys: Vec<String> = table.keys.iter()
+        .map(|key| format!("{} {}", key, order))
+        .collect();
+
+    query_builder.push(format!(" ORDER BY {} LIMIT ", keys.join(", ")));
+    query_builder.push_bind(limit);
+}
+
+// Rows on one side of the cursor, compared on the whole key of the table
+fn push_cursor(query_builder: &mut QueryBuilder<Postgres>, table: &PageTable, cursor: &EntityCursor, operator: &str) {
+    query_builder.push(format!(" AND ({}) {} (", table.keys.join(", "), operator));
+
+    let mut values = query_builder.separated(", ");
+    values.push_bind(cursor.timestamp)
+        .push_bind(cursor.symbol.clone())
+        .push_bind(cursor.timerange.clone());
+
+    if table.keys.len() > 3 {
+        values.push_bind(cursor.structure.clone());
+    }
+
+    values.push_unseparated(")");
+}
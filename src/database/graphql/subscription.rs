@@ -0,0 +1,128 @@
+use crate::{
+    database::{graphql::filters::TimerangeLabel, structures::PermissionLevel},
+    Candle,
+    OneDStructures,
+    Session,
+    Trend,
+    TwoDStructures
+};
+
+use async_graphql::{Context, Error, Subscription};
+use common::utils::log::{
+    LogFile, LogLevel,
+};
+use futures::{Stream, StreamExt, stream};
+use tokio::sync::broadcast::{
+    self,
+    error::RecvError,
+    Sender
+};
+use tokio_util::sync::CancellationToken;
+
+// Broadcast channels fed by the mutation root
+// Every committed row is pushed to its entity channel
+// And each subscription filters the rows it is interested in
+#[derive(Clone)]
+pub struct DataFeed {
+    pub candles: Sender<Candle>,
+    pub sessions: Sender<Session>,
+    pub trends: Sender<Trend>,
+    pub one_d_structures: Sender<OneDStructures>,
+    pub two_d_structures: Sender<TwoDStructures>,
+}
+
+impl DataFeed {
+    pub fn new(capacity: usize) -> Self {
+        DataFeed {
+            candles: broadcast::channel(capacity).0,
+            sessions: broadcast::channel(capacity).0,
+            trends: broadcast::channel(capacity).0,
+            one_d_structures: broadcast::channel(capacity).0,
+            two_d_structures: broadcast::channel(capacity).0,
+        }
+    }
+}
+
+// Push the committed rows to the subscribers
+// Sending only fails when nobody is listening, so we can ignore it
+pub fn publish<T>(sender: &Sender<T>, rows: Vec<T>) {
+    for row in rows {
+        sender.send(row).ok();
+    }
+}
+
+// Main GraphQL subscription root
+pub struct SubscriptionRoot;
+
+#[Subscription]
+impl SubscriptionRoot {
+    pub async fn candles(&self, ctx: &Context<'_>, symbol: String, timerange: TimerangeLabel) -> Result<impl Stream<Item = Candle>, Error> {
+        let (feed, shutdown) = subscription_feed(ctx)?;
+
+        Ok(filter_feed(feed.candles.subscribe(), shutdown, move |candle: &Candle| {
+            candle.symbol == symbol && candle.timerange == timerange.as_str()
+        }))
+    }
+
+    pub async fn sessions(&self, ctx: &Context<'_>, symbol: String) -> Result<impl Stream<Item = Session>, Error> {
+        let (feed, shutdown) = subscription_feed(ctx)?;
+
+        Ok(filter_feed(feed.sessions.subscribe(), shutdown, move |session: &Session| {
+            session.symbol == symbol
+        }))
+    }
+
+    pub async fn trends(&self, ctx: &Context<'_>, symbol: String, timerange: TimerangeLabel) -> Result<impl Stream<Item = Trend>, Error> {
+        let (feed, shutdown) = subscription_feed(ctx)?;
+
+        Ok(filter_feed(feed.trends.subscribe(), shutdown, move |trend: &Trend| {
+            trend.symbol == symbol && trend.timerange == timerange.as_str()
+        }))
+    }
+
+    pub async fn one_d_structures(&self, ctx: &Context<'_>, symbol: String, timerange: TimerangeLabel) -> Result<impl Stream<Item = OneDStructures>, Error> {
+        let (feed, shutdown) = subscription_feed(ctx)?;
+
+        Ok(filter_feed(feed.one_d_structures.subscribe(), shutdown, move |structure: &OneDStructures| {
+            structure.symbol == symbol && structure.timerange == timerange.as_str()
+        }))
+    }
+
+    pub async fn two_d_structures(&self, ctx: &Context<'_>, symbol: String, timerange: TimerangeLabel) -> Result<impl Stream<Item = TwoDStructures>, Error> {
+        let (feed, shutdown) = subscription_feed(ctx)?;
+
+        Ok(filter_feed(feed.two_d_structures.subscribe(), shutdown, move |structure: &TwoDStructures| {
+            structure.symbol == symbol && structure.timerange == timerange.as_str()
+        }))
+    }
+}
+
+// The feed and the server shutdown token, which ends the streams
+fn subscription_feed<'a>(ctx: &Context<'a>) -> Result<(&'a DataFeed, CancellationToken), Error> {
+    // Same rule as the queries, everyone authenticated can subscribe
+    let permission = ctx.data::<PermissionLevel>()?;
+    if *permission != PermissionLevel::Admin && *permission != PermissionLevel::User {
+        return Err(Error::from("Permission denied"));
+    }
+
+    Ok((ctx.data::<DataFeed>()?, ctx.data::<CancellationToken>()?.clone()))
+}
+
+// The stream ends on shutdown, so the graceful shutdown isn't held by open subscriptions
+fn filter_feed<T, F>(receiver: broadcast::Receiver<T>, shutdown: CancellationToken, keep: F) -> impl Stream<Item = T>
+where T: Clone, F: Fn(&T) -> bool {
+    stream::unfold((receiver, keep), |(mut receiver, keep)| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(row) if keep(&row) => return Some((row, (receiver, keep))),
+                Ok(_) => continue,
+                Err(RecvError::Lagged(skipped)) => {
+                    // The subscriber is too slow, it misses some rows but stays connected
+                    LogFile::add_log(LogLevel::Error, &format!("Subscription lagged, {} rows skipped", skipped)).ok();
+                },
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    })
+    .take_until(shutdown.cancelled_owned())
+}
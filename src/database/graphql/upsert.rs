@@ -0,0 +1,280 @@
+use crate::database::database::{is_retriable, InsertError, Inserted};
+
+use async_graphql::{Enum, SimpleObject};
+use sqlx::{
+    FromRow,
+    postgres::{PgRow, Postgres},
+    QueryBuilder,
+    Row
+};
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    time::Instant
+};
+
+// What to do with a row whose natural key is already stored
+#[derive(Enum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ConflictMode {
+    // Keep the stored row, so a replayed batch changes nothing
+    #[default]
+    Ignore,
+    // Replace the stored row, for corrections and live candles
+    Overwrite,
+}
+
+// Postgres refuses a statement with more bind parameters
+pub const MAX_BIND_PARAMETERS: usize = u16::MAX as usize;
+
+// Natural key of a table and the columns an overwrite replaces
+pub struct UpsertTable {
+    pub name: &'static str,
+    pub keys: &'static [&'static str],
+    pub values: &'static [&'static str],
+}
+
+impl UpsertTable {
+    // Rows per statement, each row binds every key and value
+    pub fn chunk_size(&self) -> usize {
+        MAX_BIND_PARAMETERS / (self.keys.len() + self.values.len())
+    }
+}
+
+pub const CANDLE_UPSERT: UpsertTable = UpsertTable {
+    name: "candles",
+    keys: &["symbol", "timerange", "timestamp"],
+    values: &["open", "high", "low", "close", "volume", "direction"],
+};
+
+pub const SESSION_UPSERT: UpsertTable = UpsertTable {
+    name: "sessions",
+    keys: &["symbol", "label", "start_time"],
+    values: &["end_time", "high", "low", "open", "close", "volume"],
+};
+
+pub const TREND_UPSERT: UpsertTable = UpsertTable {
+    name: "trends",
+    keys: &["symbol", "timerange", "start_time"],
+    values: &["end_time", "direction", "high", "low"],
+};
+
+pub const ONE_D_STRUCTURE_UPSERT: UpsertTable = UpsertTable {
+    name: "one_d_structures",
+    keys: &["symbol", "structure", "timerange", "timestamp"],
+    values: &["price", "direction"],
+};
+
+pub const TWO_D_STRUCTURE_UPSERT: UpsertTable = UpsertTable {
+    name: "two_d_structures",
+    keys: &["symbol", "structure", "timerange", "timestamp"],
+    values: &["high", "low", "direction"],
+};
+
+// A row returned by an upsert
+// xmax is zero for a new row and set for an updated one
+pub struct Upserted<T> {
+    pub row: T,
+    pub inserted: bool,
+}
+
+impl<'r, T: FromRow<'r, PgRow>> FromRow<'r, PgRow> for Upserted<T> {
+    fn from_row(row: &'r PgRow) -> Result<Self, sqlx::Error> {
+        Ok(Upserted {
+            inserted: row.try_get("inserted")?,
+            row: T::from_row(row)?,
+        })
+    }
+}
+
+// Rows written by an upsert, and what happened to the ones received
+pub struct Upsert<T> {
+    pub rows: Vec<T>,
+    pub result: EntityResult,
+    // The insert failed on an error that may pass on a second try
+    pub retriable: bool,
+}
+
+// What an insert did with the rows of one entity kind
+#[derive(SimpleObject, Clone, Debug, Default)]
+pub struct EntityResult {
+    pub received: usize,
+    // Inserted and updated rows
+    pub written: usize,
+    pub inserted: usize,
+    pub updated: usize,
+    // Duplicates of the batch, conflicts kept by Ignore and unchanged rows
+    // Left at zero when the insert failed
+    pub skipped: usize,
+    // Refused before reaching the database
+    pub rejected: Vec<RejectedRow>,
+    // Attempts made after the first one, over every chunk
+    pub retries: u32,
+    pub elapsed_ms: u64,
+    // Set when the insert failed, written then only counts the chunks committed before
+    pub error: Option<String>,
+}
+
+#[derive(SimpleObject, Clone, Debug)]
+pub struct RejectedRow {
+    // Position of the row in the list received
+    pub index: usize,
+    pub reason: String,
+}
+
+impl<T> Upsert<T> {
+    pub fn new(received: usize, rejected: Vec<RejectedRow>, inserted: Inserted<Upserted<T>>, started: Instant) -> Self {
+        let Inserted { rows, retries } = inserted;
+        let new_rows = rows.iter().filter(|row| row.inserted).count();
+
+        Upsert {
+            result: EntityResult {
+                received,
+                written: rows.len(),
+                inserted: new_rows,
+                updated: rows.len() - new_rows,
+                skipped: received - rejected.len() - rows.len(),
+                rejected,
+                retries,
+                elapsed_ms: started.elapsed().as_millis() as u64,
+                error: None,
+            },
+            rows: rows.into_iter().map(|row| row.row).collect(),
+            retriable: false,
+        }
+    }
+
+    // The chunks written before the error stay in rows, they are committed on the pool
+    pub fn failed(received: usize, rejected: Vec<RejectedRow>, inserted: Inserted<Upserted<T>>, error: InsertError, started: Instant) -> Self {
+        let rows = inserted.rows;
+        let retriable = is_retriable(&error.error);
+        let new_rows = rows.iter().filter(|row| row.inserted).count();
+
+        Upsert {
+            result: EntityResult {
+                received,
+                written: rows.len(),
+                inserted: new_rows,
+                updated: rows.len() - new_rows,
+                rejected,
+                retries: error.retries,
+                elapsed_ms: started.elapsed().as_millis() as u64,
+                error: Some(error.error.to_string()),
+                ..EntityResult::default()
+            },
+            rows: rows.into_iter().map(|row| row.row).collect(),
+            retriable,
+        }
+    }
+
+    // Left out because an earlier insert of the same transaction failed
+    pub fn not_attempted(received: usize) -> Self {
+        Upsert {
+            rows: Vec::new(),
+            result: EntityResult {
+                received,
+                error: Some("Not attempted, an earlier insert of the transaction failed".to_string()),
+                ..EntityResult::default()
+            },
+            retriable: false,
+        }
+    }
+
+    pub fn is_failed(&self) -> bool {
+        self.result.error.is_some()
+    }
+}
+
+impl EntityResult {
+    // Not sent in atomic mode, because a row of the payload was rejected
+    pub fn refused(received: usize, rejected: Vec<RejectedRow>) -> Self {
+        EntityResult {
+            received,
+            rejected,
+            error: Some("Not written, the payload has rejected rows".to_string()),
+            ..EntityResult::default()
+        }
+    }
+
+    // The rows written were undone, only the rejected ones stay relevant
+    pub fn rolled_back(self) -> Self {
+        EntityResult {
+            received: self.received,
+            rejected: self.rejected,
+            retries: self.retries,
+            elapsed_ms: self.elapsed_ms,
+            error: self.error.or_else(|| Some("Rolled back".to_string())),
+            ..EntityResult::default()
+        }
+    }
+}
+
+// Keep the last row of every natural key
+// Postgres refuses to update the same row twice in one statement
+pub fn dedup_by_key<'a, T, K: Hash + Eq>(rows: &'a [T], key: impl Fn(&'a T) -> K) -> Vec<&'a T> {
+    let mut positions = HashMap::with_capacity(rows.len());
+
+    for (position, row) in rows.iter().enumerate() {
+        positions.insert(key(row), position);
+    }
+
+    rows.iter()
+        .enumerate()
+        .filter(|(position, row)| positions.get(&key(row)) == Some(position))
+        .map(|(_, row)| row)
+        .collect()
+}
+
+// Push the conflict clause then return the written rows with their inserted flag
+// An overwrite leaves the identical rows untouched, so they count as skipped
+pub fn push_upsert(query_builder: &mut QueryBuilder<Postgres>, table: &UpsertTable, mode: ConflictMode, returning: &str) {
+    query_builder.push(format!(" ON CONFLICT ({}) ", table.keys.join(", ")));
+
+    match mode {
+        ConflictMode::Ignore => {
+            query_builder.push("DO NOTHING");
+        },
+        ConflictMode::Overwrite => {
+            let assignments: Vec<String> = table.values.iter()
+                .map(|column| format!("{} = EXCLUDED.{}", column, column))
+                .collect();
+            let stored: Vec<String> = table.values.iter()
+                .map(|column| format!("{}.{}", table.name, column))
+                .collect();
+            let excluded: Vec<String> = table.values.iter()
+                .map(|column| format!("EXCLUDED.{}", column))
+                .collect();
+
+            query_builder.push(format!(
+                "DO UPDATE SET {} WHERE ({}) IS DISTINCT FROM ({})",
+                assignments.join(", "),
+                stored.join(", "),
+                excluded.join(", ")
+            ));
+        },
+    }
+
+    query_builder.push(format!(" RETURNING {}, (xmax = 0) AS inserted", returning));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MAX_BIND_PARAMETERS, CANDLE_UPSERT, ONE_D_STRUCTURE_UPSERT, SESSION_UPSERT, TREND_UPSERT, TWO_D_STRUCTURE_UPSERT};
+
+    #[test]
+    fn chunks_stay_under_the_bind_parameter_limit() {
+        for table in [CANDLE_UPSERT, SESSION_UPSERT, TREND_UPSERT, ONE_D_STRUCTURE_UPSERT, TWO_D_STRUCTURE_UPSERT] {
+            let columns = table.keys.len() + table.values.len();
+            let size = table.chunk_size();
+
+            assert!(size * columns <= MAX_BIND_PARAMETERS, "{} binds too many parameters", table.name);
+            assert!((size + 1) * columns > MAX_BIND_PARAMETERS, "{} could hold one more row", table.name);
+        }
+    }
+
+    #[test]
+    fn chunk_size_follows_the_columns() {
+        // 9 columns for candles, 6 for the one dimension structures
+        assert_eq!(CANDLE_UPSERT.chunk_size(), 7281);
+        assert_eq!(ONE_D_STRUCTURE_UPSERT.chunk_size(), 10922);
+    }
+}
@@ -0,0 +1,110 @@
+use crate::database::graphql::{
+    aggregation::timerange_seconds,
+    upsert::RejectedRow
+};
+
+use common::{
+    entities::{
+        candle::CandleInput,
+        session::SessionInput,
+        structures::{OneDStructuresInput, TwoDStructuresInput},
+        trend::TrendInput
+    },
+    utils::log::{LogFile, LogLevel}
+};
+
+// Split the rows worth sending from the ones the database would store wrongly or refuse
+// A rejected row is reported with its position in the batch received
+pub fn validate_rows<T>(rows: &[T], validate: fn(&T) -> Result<(), String>) -> (Vec<&T>, Vec<RejectedRow>) {
+    let mut valid = Vec::with_capacity(rows.len());
+    let mut rejected = Vec::new();
+
+    for (index, row) in rows.iter().enumerate() {
+        match validate(row) {
+            Ok(()) => valid.push(row),
+            Err(reason) => rejected.push(RejectedRow { index, reason }),
+        }
+    }
+
+    (valid, rejected)
+}
+
+pub fn log_rejected(name: &str, rejected: &[RejectedRow]) {
+    for row in rejected {
+        LogFile::add_log(LogLevel::Error, &format!("Rejected {} at index {}: {}", name, row.index, row.reason)).ok();
+    }
+}
+
+pub fn validate_candle(candle: &CandleInput) -> Result<(), String> {
+    check_timerange(&candle.timerange)?;
+    check_finite(&[candle.open, candle.high, candle.low, candle.close, candle.volume])?;
+    check_range(candle.high, candle.low, &[candle.open, candle.close])?;
+    check_volume(candle.volume)
+}
+
+pub fn validate_session(session: &SessionInput) -> Result<(), String> {
+    if session.end_time <= session.start_time {
+        return Err(format!("end_time {} must be after start_time {}", session.end_time.to_rfc3339(), session.start_time.to_rfc3339()));
+    }
+
+    check_finite(&[session.open, session.high, session.low, session.close, session.volume])?;
+    check_range(session.high, session.low, &[session.open, session.close])?;
+    check_volume(session.volume)
+}
+
+pub fn validate_trend(trend: &TrendInput) -> Result<(), String> {
+    check_timerange(&trend.timerange)?;
+
+    if trend.end_time <= trend.start_time {
+        return Err(format!("end_time {} must be after start_time {}", trend.end_time.to_rfc3339(), trend.start_time.to_rfc3339()));
+    }
+
+    check_finite(&[trend.high, trend.low])?;
+    check_range(trend.high, trend.low, &[])
+}
+
+pub fn validate_one_d_structure(structure: &OneDStructuresInput) -> Result<(), String> {
+    check_timerange(&structure.timerange)?;
+    check_finite(&[structure.price])
+}
+
+pub fn validate_two_d_structure(structure: &TwoDStructuresInput) -> Result<(), String> {
+    check_timerange(&structure.timerange)?;
+    check_finite(&[structure.high, structure.low])?;
+    check_range(structure.high, structure.low, &[])
+}
+
+// Rows of an unknown timerange could never be queried back
+fn check_timerange(timerange: &str) -> Result<(), String> {
+    timerange_seconds(timerange)
+        .map(|_| ())
+        .ok_or_else(|| format!("Unknown timerange {}", timerange))
+}
+
+fn check_finite(values: &[f64]) -> Result<(), String> {
+    if !values.iter().all(|value| value.is_finite()) {
+        return Err("Prices and volume must be finite numbers".to_string());
+    }
+
+    Ok(())
+}
+
+// The high and low bound every other price of the row
+fn check_range(high: f64, low: f64, prices: &[f64]) -> Result<(), String> {
+    if low > high {
+        return Err(format!("low {} is above high {}", low, high));
+    }
+
+    match prices.iter().find(|price| **price < low || **price > high) {
+        Some(price) => Err(format!("Price {} is outside low {} and high {}", price, low, high)),
+        None => Ok(()),
+    }
+}
+
+fn check_volume(volume: f64) -> Result<(), String> {
+    if volume < 0.0 {
+        return Err(format!("Negative volume {}", volume));
+    }
+
+    Ok(())
+}
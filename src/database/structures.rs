@@ -1,7 +1,107 @@
+use crate::utils::env::{env_millis, env_value};
+
+use std::time::Duration;
+
 pub struct Permission(pub PermissionLevel);
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum PermissionLevel {
     Admin,
     User,
+}
+
+// How an insert on the pool is retried
+// Only transient errors are retried, see is_retriable
+// No attempt starts once the deadline has passed since the first one
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    pub deadline: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_secs(2),
+            deadline: Duration::from_secs(10),
+        }
+    }
+}
+
+impl RetryPolicy {
+    // The defaults, overridden by the PARAGON_INSERT_* variables that are set
+    pub fn from_env() -> Self {
+        let default = RetryPolicy::default();
+
+        RetryPolicy {
+            max_attempts: env_value("PARAGON_INSERT_MAX_ATTEMPTS").unwrap_or(default.max_attempts),
+            initial_backoff: env_millis("PARAGON_INSERT_BACKOFF_MS").unwrap_or(default.initial_backoff),
+            max_backoff: env_millis("PARAGON_INSERT_MAX_BACKOFF_MS").unwrap_or(default.max_backoff),
+            deadline: env_millis("PARAGON_INSERT_DEADLINE_MS").unwrap_or(default.deadline),
+        }
+    }
+
+    // Doubles on every retry, half of it is random
+    // So the writers that failed together don't retry together
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let backoff = self.initial_backoff
+            .saturating_mul(2u32.saturating_pow(retry))
+            .min(self.max_backoff);
+
+        let half = backoff / 2;
+
+        half + half.mul_f64(rand::random::<f64>())
+    }
+
+    // Why no other attempt follows a failure, None when it is retried after the backoff
+    pub fn give_up(&self, retriable: bool, retries: u32, elapsed: Duration, backoff: Duration) -> Option<&'static str> {
+        if !retriable {
+            Some("not retriable")
+        } else if retries + 1 >= self.max_attempts {
+            Some("no attempt left")
+        } else if elapsed + backoff > self.deadline {
+            Some("deadline reached")
+        } else {
+            None
+        }
+    }
+
+    // Time left for an attempt started now
+    pub fn remaining(&self, elapsed: Duration) -> Duration {
+        self.deadline.saturating_sub(elapsed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RetryPolicy;
+
+    use std::time::Duration;
+
+    #[test]
+    fn backoff_doubles_up_to_the_max() {
+        let policy = RetryPolicy::default();
+
+        for (retry, full) in [(0, 50), (1, 100), (2, 200), (5, 1600), (6, 2000), (40, 2000)] {
+            let full = Duration::from_millis(full);
+            let backoff = policy.backoff(retry);
+
+            assert!(backoff >= full / 2 && backoff <= full, "retry {}: {:?} outside {:?}", retry, backoff, full);
+        }
+    }
+
+    #[test]
+    fn gives_up_on_errors_attempts_and_deadline() {
+        let policy = RetryPolicy::default();
+        let backoff = Duration::from_millis(100);
+
+        assert_eq!(policy.give_up(false, 0, Duration::ZERO, backoff), Some("not retriable"));
+        assert_eq!(policy.give_up(true, 4, Duration::ZERO, backoff), Some("no attempt left"));
+        assert_eq!(policy.give_up(true, 1, Duration::from_millis(9950), backoff), Some("deadline reached"));
+        assert_eq!(policy.give_up(true, 3, Duration::from_secs(1), backoff), None);
+    }
 }
\ No newline at end of file
@@ -0,0 +1,76 @@
+use crate::{
+    database::database::last_insert,
+    health::structures::{DatabaseStatus, FeedStatus, HealthState, Status},
+    supervisor::{
+        structures::SubsystemStatus,
+        supervisor::health_report
+    }
+};
+
+use axum::{
+    Extension,
+    http::StatusCode,
+    Json,
+};
+use std::time::Duration;
+use tokio::time::timeout;
+
+// How long the database may take to answer the readiness probe
+const READY_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Liveness, answering at all is enough
+pub async fn healthz() -> StatusCode {
+    StatusCode::OK
+}
+
+// Ready once Postgres answers and every subsystem is serving
+pub async fn readyz(Extension(state): Extension<HealthState>) -> StatusCode {
+    if is_ready(&state).await {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    }
+}
+
+pub async fn status(Extension(state): Extension<HealthState>) -> Json<Status> {
+    let reachable = database_reachable(&state).await;
+
+    let subsystems = health_report(&state.subsystems);
+    let ready = reachable && subsystems.iter().all(|subsystem| subsystem.status == SubsystemStatus::Running);
+
+    let database = DatabaseStatus {
+        reachable,
+        connections: state.pool.size(),
+        idle_connections: state.pool.num_idle(),
+        max_connections: state.pool.options().get_max_connections(),
+        last_insert: last_insert(),
+    };
+
+    let feed = state.feed.as_ref().map(|feed| FeedStatus {
+        receivers: feed.clients.lock().unwrap().clients.len(),
+        publishers: feed.publishers.lock().unwrap().len(),
+    });
+
+    Json(Status {
+        ready,
+        database,
+        subsystems,
+        feed,
+    })
+}
+
+async fn is_ready(state: &HealthState) -> bool {
+    // The listener of this server is bound since it answered
+    // The others are checked through the supervisor
+    let subsystems_running = health_report(&state.subsystems)
+        .iter()
+        .all(|subsystem| subsystem.status == SubsystemStatus::Running);
+
+    subsystems_running && database_reachable(state).await
+}
+
+async fn database_reachable(state: &HealthState) -> bool {
+    let ping = sqlx::query("SELECT 1").execute(&state.pool);
+
+    matches!(timeout(READY_TIMEOUT, ping).await, Ok(Ok(_)))
+}
@@ -0,0 +1,50 @@
+use crate::{
+    supervisor::structures::{Health, SubsystemHealth},
+    websocket::structures::{Clients, Publishers}
+};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::PgPool;
+
+// What the health endpoints of a server look at
+#[derive(Clone)]
+pub struct HealthState {
+    pub pool: PgPool,
+    pub subsystems: Health,
+    // Only set on the websocket server
+    pub feed: Option<FeedState>,
+}
+
+#[derive(Clone)]
+pub struct FeedState {
+    pub clients: Clients,
+    pub publishers: Publishers,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Status {
+    pub ready: bool,
+    pub database: DatabaseStatus,
+    pub subsystems: Vec<SubsystemHealth>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub feed: Option<FeedStatus>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DatabaseStatus {
+    pub reachable: bool,
+    // Open connections, idle ones included
+    pub connections: u32,
+    pub idle_connections: usize,
+    pub max_connections: u32,
+    pub last_insert: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FeedStatus {
+    pub receivers: usize,
+    // Only the count, /status is unauthenticated
+    // The names are listed by /ws/publishers, for admins
+    pub publishers: usize,
+}
@@ -1,5 +1,8 @@
 pub mod utils;
 pub mod database;
+pub mod health;
+pub mod metrics;
+pub mod supervisor;
 pub mod websocket;
 
 pub use database::database::launch_database;
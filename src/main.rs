@@ -1,39 +1,95 @@
 use server::{
-    launch_database, launch_websocket_server
+    launch_database, launch_websocket_server,
+    supervisor::{
+        structures::{Health, SupervisorSettings},
+        supervisor::supervise
+    },
+    utils::{
+        shutdown::shutdown_signal,
+        telemetry::init_tracing
+    },
+    websocket::structures::WebsocketSettings
 };
 use common::{Config, Secrets};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex}
+};
+use tokio_util::sync::CancellationToken;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let telemetry = init_tracing()?;
+
     let config = Config::global();
     let secrets = Secrets::global();
 
-    let websocket_runner = tokio::spawn(async move {
-        let websocket_address = format!("{}:{}", config.server.websocket.address, config.server.websocket.port);
-        
-        launch_websocket_server(websocket_address).await
-            .map_err(|e| format!("WebSocket server error: {}", e))
+    // Read once, a restarted server keeps the same settings
+    let websocket_settings = WebsocketSettings::from_env();
+
+    // Cancelled on SIGINT/SIGTERM or when a subsystem exhausts its failure budget
+    // Each server then drains before its task returns
+    let shutdown = CancellationToken::new();
+
+    // A failed server is restarted while the other one keeps serving
+    let supervisor_settings = SupervisorSettings::from_env();
+    let health: Health = Arc::new(Mutex::new(HashMap::new()));
+
+    let websocket_runner = tokio::spawn({
+        let shutdown = shutdown.clone();
+        let health = Arc::clone(&health);
+
+        async move {
+            let result = supervise("websocket", supervisor_settings, Arc::clone(&health), shutdown.clone(), |shutdown| {
+                let websocket_address = format!("{}:{}", config.server.websocket.address, config.server.websocket.port);
+
+                launch_websocket_server(websocket_address, secrets.server.database.url.clone(), websocket_settings, Arc::clone(&health), shutdown)
+            }).await
+                .map_err(|e| format!("WebSocket server error: {}", e));
+
+            shutdown.cancel();
+            result
+        }
     });
 
-    let database_runner = tokio::spawn(async move {
-        let database_address = format!("{}:{}", config.server.database.address, config.server.database.port);
-        
-        launch_database(database_address, secrets.server.database.url.clone()).await
-            .map_err(|e| format!("Database error: {}", e))
+    let database_runner = tokio::spawn({
+        let shutdown = shutdown.clone();
+        let health = Arc::clone(&health);
+
+        async move {
+            let result = supervise("database", supervisor_settings, Arc::clone(&health), shutdown.clone(), |shutdown| {
+                let database_address = format!("{}:{}", config.server.database.address, config.server.database.port);
+
+                launch_database(database_address, secrets.server.database.url.clone(), Arc::clone(&health), shutdown)
+            }).await
+                .map_err(|e| format!("Database error: {}", e));
+
+            shutdown.cancel();
+            result
+        }
     });
 
     tokio::select! {
-        result = websocket_runner => match result {
-            Ok(Ok(())) => {},
-            Ok(Err(e)) => eprintln!("Error launching WebSocket server: {}", e),
-            Err(e) => eprintln!("WebSocket task failed: {}", e),
-        },
-        result = database_runner => match result {
-            Ok(Ok(())) => {},
-            Ok(Err(e)) => eprintln!("Error launching Database: {}", e),
-            Err(e) => eprintln!("Database task failed: {}", e),
-        }
+        _ = shutdown_signal() => shutdown.cancel(),
+        _ = shutdown.cancelled() => {},
+    }
+
+    // Wait for both servers to drain before exiting
+    let (websocket_result, database_result) = tokio::join!(websocket_runner, database_runner);
+
+    match websocket_result {
+        Ok(Ok(())) => {},
+        Ok(Err(e)) => eprintln!("Error launching WebSocket server: {}", e),
+        Err(e) => eprintln!("WebSocket task failed: {}", e),
     }
 
+    match database_result {
+        Ok(Ok(())) => {},
+        Ok(Err(e)) => eprintln!("Error launching Database: {}", e),
+        Err(e) => eprintln!("Database task failed: {}", e),
+    }
+
+    telemetry.shutdown();
+
     Ok(())
 }
@@ -0,0 +1,107 @@
+use crate::{
+    metrics::structures::Metrics,
+    websocket::structures::ClientRole
+};
+
+use axum::{
+    http::{header, StatusCode},
+    response::IntoResponse,
+};
+use common::utils::log::{
+    LogFile,
+    LogLevel
+};
+use prometheus::{
+    HistogramOpts,
+    HistogramVec,
+    IntCounter,
+    IntCounterVec,
+    IntGaugeVec,
+    Opts,
+    Registry,
+    TextEncoder
+};
+use sqlx::PgPool;
+use std::{
+    collections::HashMap,
+    sync::{Mutex, OnceLock}
+};
+
+static METRICS: OnceLock<Metrics> = OnceLock::new();
+
+impl Metrics {
+    // Shared by both servers, so a single scrape sees the whole process
+    pub fn global() -> &'static Metrics {
+        METRICS.get_or_init(Metrics::new)
+    }
+
+    fn new() -> Self {
+        let registry = Registry::new();
+
+        let metrics = Metrics {
+            websocket_connections: IntGaugeVec::new(Opts::new("websocket_connections", "Open websocket connections"), &["role"]).unwrap(),
+            websocket_connections_total: IntCounterVec::new(Opts::new("websocket_connections_total", "Accepted websocket connections"), &["role"]).unwrap(),
+            websocket_messages_in: IntCounterVec::new(Opts::new("websocket_messages_in_total", "Frames received from the websocket clients"), &["role"]).unwrap(),
+            websocket_messages_out: IntCounter::new("websocket_messages_out_total", "Frames written to the websocket clients").unwrap(),
+            websocket_dropped_messages: IntCounter::new("websocket_dropped_messages_total", "Frames dropped by the client queues").unwrap(),
+            graphql_duration: HistogramVec::new(HistogramOpts::new("graphql_operation_duration_seconds", "GraphQL latency by root field"), &["operation"]).unwrap(),
+            insert_retries: IntCounterVec::new(Opts::new("database_insert_retries_total", "Insert attempts that failed and were retried"), &["table"]).unwrap(),
+            insert_failures: IntCounterVec::new(Opts::new("database_insert_failures_total", "Inserts that failed after every attempt"), &["table"]).unwrap(),
+            rows_inserted: IntCounterVec::new(Opts::new("database_rows_inserted_total", "Rows written by the inserts"), &["table"]).unwrap(),
+            pool_connections: IntGaugeVec::new(Opts::new("database_pool_connections", "Open Postgres connections, idle ones included"), &["pool"]).unwrap(),
+            pool_idle_connections: IntGaugeVec::new(Opts::new("database_pool_idle_connections", "Idle Postgres connections"), &["pool"]).unwrap(),
+            pools: Mutex::new(HashMap::new()),
+            registry,
+        };
+
+        // Names are unique, so registering can't fail
+        metrics.registry.register(Box::new(metrics.websocket_connections.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.websocket_connections_total.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.websocket_messages_in.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.websocket_messages_out.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.websocket_dropped_messages.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.graphql_duration.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.insert_retries.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.insert_failures.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.rows_inserted.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.pool_connections.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.pool_idle_connections.clone())).unwrap();
+
+        metrics
+    }
+
+    // Report the size of a pool on every scrape
+    // A restarted server replaces the pool it registered before
+    pub fn watch_pool(&self, name: &'static str, pool: PgPool) {
+        self.pools.lock().unwrap().insert(name, pool);
+    }
+
+    pub fn role_label(role: &ClientRole) -> &'static str {
+        match role {
+            ClientRole::Sender => "sender",
+            ClientRole::Receiver => "receiver",
+        }
+    }
+
+    fn refresh_pools(&self) {
+        for (name, pool) in self.pools.lock().unwrap().iter() {
+            self.pool_connections.with_label_values(&[*name]).set(pool.size() as i64);
+            self.pool_idle_connections.with_label_values(&[*name]).set(pool.num_idle() as i64);
+        }
+    }
+}
+
+// Prometheus text format
+pub async fn metrics_handler() -> impl IntoResponse {
+    let metrics = Metrics::global();
+    metrics.refresh_pools();
+
+    match TextEncoder::new().encode_to_string(&metrics.registry.gather()) {
+        Ok(body) => (StatusCode::OK, [(header::CONTENT_TYPE, prometheus::TEXT_FORMAT)], body),
+        Err(e) => {
+            LogFile::add_log(LogLevel::Error, &format!("Failed to encode metrics: {}", e)).ok();
+
+            (StatusCode::INTERNAL_SERVER_ERROR, [(header::CONTENT_TYPE, "text/plain")], String::new())
+        }
+    }
+}
@@ -0,0 +1,2 @@
+pub mod metrics;
+pub mod structures;
\ No newline at end of file
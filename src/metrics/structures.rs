@@ -0,0 +1,27 @@
+use prometheus::{HistogramVec, IntCounter, IntCounterVec, IntGaugeVec, Registry};
+use sqlx::PgPool;
+use std::{
+    collections::HashMap,
+    sync::Mutex
+};
+
+// Every metric exported on /metrics
+pub struct Metrics {
+    pub registry: Registry,
+    // By client role
+    pub websocket_connections: IntGaugeVec,
+    pub websocket_connections_total: IntCounterVec,
+    pub websocket_messages_in: IntCounterVec,
+    pub websocket_messages_out: IntCounter,
+    pub websocket_dropped_messages: IntCounter,
+    // By operation name, unknown names grouped as other
+    pub graphql_duration: HistogramVec,
+    // By table
+    pub insert_retries: IntCounterVec,
+    pub insert_failures: IntCounterVec,
+    pub rows_inserted: IntCounterVec,
+    // By pool, refreshed on every scrape
+    pub pool_connections: IntGaugeVec,
+    pub pool_idle_connections: IntGaugeVec,
+    pub pools: Mutex<HashMap<&'static str, PgPool>>,
+}
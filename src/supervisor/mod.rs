@@ -0,0 +1,2 @@
+pub mod structures;
+pub mod supervisor;
\ No newline at end of file
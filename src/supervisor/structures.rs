@@ -0,0 +1,68 @@
+use crate::utils::env::{env_millis, env_value};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration
+};
+
+// Health of every supervised subsystem, by name
+pub type Health = Arc<Mutex<HashMap<String, SubsystemHealth>>>;
+
+// How a failed subsystem is restarted
+// The process only exits once more than failure_budget failures happen within budget_window
+#[derive(Clone, Copy, Debug)]
+pub struct SupervisorSettings {
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    pub failure_budget: usize,
+    pub budget_window: Duration,
+}
+
+impl Default for SupervisorSettings {
+    fn default() -> Self {
+        SupervisorSettings {
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+            failure_budget: 10,
+            budget_window: Duration::from_secs(600),
+        }
+    }
+}
+
+impl SupervisorSettings {
+    // The defaults, overridden by the PARAGON_SUPERVISOR_* variables that are set
+    pub fn from_env() -> Self {
+        let default = SupervisorSettings::default();
+
+        SupervisorSettings {
+            initial_backoff: env_millis("PARAGON_SUPERVISOR_INITIAL_BACKOFF_MS").unwrap_or(default.initial_backoff),
+            max_backoff: env_millis("PARAGON_SUPERVISOR_MAX_BACKOFF_MS").unwrap_or(default.max_backoff),
+            failure_budget: env_value("PARAGON_SUPERVISOR_FAILURE_BUDGET").unwrap_or(default.failure_budget),
+            budget_window: env_millis("PARAGON_SUPERVISOR_BUDGET_WINDOW_MS").unwrap_or(default.budget_window),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SubsystemStatus {
+    Running,
+    // Waiting for the backoff before the next start
+    Restarting,
+    // The failure budget is exhausted
+    Failed,
+    Stopped,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SubsystemHealth {
+    pub name: String,
+    pub status: SubsystemStatus,
+    pub restarts: u32,
+    pub last_error: Option<String>,
+    // When the status last changed
+    pub since: DateTime<Utc>,
+}
@@ -0,0 +1,108 @@
+use crate::supervisor::structures::{Health, SubsystemHealth, SubsystemStatus, SupervisorSettings};
+
+use chrono::Utc;
+use common::utils::log::{
+    LogFile,
+    LogLevel
+};
+use std::{
+    collections::VecDeque,
+    future::Future,
+    time::Instant
+};
+use tokio::time::sleep;
+use tokio_util::sync::CancellationToken;
+
+// Run a subsystem until shutdown, restarting it with exponential backoff when it fails
+// So a crashed server doesn't take the other one with it
+// Returns an error once the failure budget is exhausted
+pub async fn supervise<F, Fut>(name: &str, settings: SupervisorSettings, health: Health, shutdown: CancellationToken, launch: F) -> Result<(), String>
+where F: Fn(CancellationToken) -> Fut, Fut: Future<Output = Result<(), Box<dyn std::error::Error>>> + Send + 'static {
+    let mut failures: VecDeque<Instant> = VecDeque::new();
+    let mut restarts = 0;
+    let mut backoff = settings.initial_backoff;
+
+    loop {
+        set_health(&health, name, SubsystemStatus::Running, restarts, None);
+
+        let started = Instant::now();
+
+        // Each attempt runs in its own task, so a panic is a failure like any other
+        let attempt = launch(shutdown.clone());
+        let result = match tokio::spawn(async move { attempt.await.map_err(|e| e.to_string()) }).await {
+            Ok(result) => result,
+            Err(e) => Err(e.to_string()),
+        };
+
+        if shutdown.is_cancelled() {
+            set_health(&health, name, SubsystemStatus::Stopped, restarts, result.err());
+
+            return Ok(());
+        }
+
+        // A server only returns on its own when something went wrong
+        let error = result.err().unwrap_or_else(|| "Stopped unexpectedly".to_string());
+
+        // Only the failures of the window count against the budget
+        let now = Instant::now();
+        failures.push_back(now);
+        while failures.front().is_some_and(|failure| now.duration_since(*failure) > settings.budget_window) {
+            failures.pop_front();
+        }
+
+        if failures.len() > settings.failure_budget {
+            LogFile::add_log(LogLevel::Error, &format!("{} failed {} times, giving up: {}", name, failures.len(), error)).ok();
+            set_health(&health, name, SubsystemStatus::Failed, restarts, Some(error.clone()));
+
+            return Err(format!("{} failed too many times: {}", name, error));
+        }
+
+        // A run that lasted longer than the longest backoff was healthy
+        // So the next failure starts over from the initial backoff
+        if started.elapsed() > settings.max_backoff {
+            backoff = settings.initial_backoff;
+        }
+
+        LogFile::add_log(LogLevel::Error, &format!("{} failed, restarting in {:?}: {}", name, backoff, error)).ok();
+        set_health(&health, name, SubsystemStatus::Restarting, restarts, Some(error));
+
+        tokio::select! {
+            _ = sleep(backoff) => {},
+            _ = shutdown.cancelled() => {
+                set_health(&health, name, SubsystemStatus::Stopped, restarts, None);
+
+                return Ok(());
+            },
+        }
+
+        backoff = (backoff * 2).min(settings.max_backoff);
+        restarts += 1;
+    }
+}
+
+// Snapshot of every subsystem, sorted by name
+pub fn health_report(health: &Health) -> Vec<SubsystemHealth> {
+    let mut report: Vec<SubsystemHealth> = health.lock().unwrap()
+        .values()
+        .cloned()
+        .collect();
+
+    report.sort_by(|a, b| a.name.cmp(&b.name));
+
+    report
+}
+
+fn set_health(health: &Health, name: &str, status: SubsystemStatus, restarts: u32, error: Option<String>) {
+    let mut guard = health.lock().unwrap();
+
+    // Keep the last error around once the subsystem is back up
+    let last_error = error.or_else(|| guard.get(name).and_then(|current| current.last_error.clone()));
+
+    guard.insert(name.to_string(), SubsystemHealth {
+        name: name.to_string(),
+        status,
+        restarts,
+        last_error,
+        since: Utc::now(),
+    });
+}
@@ -19,6 +19,8 @@ pub struct Claim {
     pub role: Option<String>,
     #[serde(default, skip_serializing_if = "Option::is_none")] // Only needs for websocket
     pub permissionlevel: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")] // Only needs for websocket senders
+    pub publisher: Option<String>,
     pub exp: usize,
 }
 
@@ -34,9 +36,6 @@ pub fn verify_jwt(token: &str) -> Result<TokenData<Claim>, Error> {
 }
 
 pub fn create_jwt(sub: String, username: String, role: Option<String>, permissionlevel: Option<String>, time: usize) -> Result<String, Error> {
-    let secrets = Secrets::global();
-    let secret_key = secrets.server.common.secret_key.as_bytes();
-
     let now = Utc::now().timestamp() as usize;
     let exp = now + time; // Add the required time for the expiration token
 
@@ -45,12 +44,37 @@ pub fn create_jwt(sub: String, username: String, role: Option<String>, permissio
         username,
         role,
         permissionlevel,
+        publisher: None,
         exp,
     };
 
+    encode_jwt(&claim)
+}
+
+// Token of a websocket sender publishing under its own name
+pub fn create_publisher_jwt(sub: String, username: String, publisher: String, time: usize) -> Result<String, Error> {
+    let now = Utc::now().timestamp() as usize;
+    let exp = now + time; // Add the required time for the expiration token
+
+    let claim = Claim {
+        sub,
+        username,
+        role: Some("sender".to_string()),
+        permissionlevel: None,
+        publisher: Some(publisher),
+        exp,
+    };
+
+    encode_jwt(&claim)
+}
+
+fn encode_jwt(claim: &Claim) -> Result<String, Error> {
+    let secrets = Secrets::global();
+    let secret_key = secrets.server.common.secret_key.as_bytes();
+
     jsonwebtoken::encode(
         &jsonwebtoken::Header::default(),
-        &claim,
+        claim,
         &jsonwebtoken::EncodingKey::from_secret(secret_key),
     )
 }
\ No newline at end of file
@@ -0,0 +1,28 @@
+use common::utils::log::{
+    LogFile,
+    LogLevel
+};
+use std::{
+    str::FromStr,
+    time::Duration
+};
+
+// Value of an environment variable, None when it is unset
+// An invalid value is logged and ignored, so the default is kept
+pub fn env_value<T: FromStr>(name: &str) -> Option<T> {
+    let value = std::env::var(name).ok()?;
+
+    match value.trim().parse() {
+        Ok(parsed) => Some(parsed),
+        Err(_) => {
+            LogFile::add_log(LogLevel::Error, &format!("Ignoring invalid {}: {}", name, value)).ok();
+
+            None
+        }
+    }
+}
+
+// A duration given in milliseconds
+pub fn env_millis(name: &str) -> Option<Duration> {
+    env_value::<u64>(name).map(Duration::from_millis)
+}
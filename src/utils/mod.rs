@@ -1 +1,4 @@
-pub mod auth;
\ No newline at end of file
+pub mod auth;
+pub mod env;
+pub mod shutdown;
+pub mod telemetry;
\ No newline at end of file
@@ -0,0 +1,43 @@
+use common::utils::log::{
+    LogFile,
+    LogLevel
+};
+use std::time::Duration;
+use tokio::signal;
+
+// How long the servers may take to drain once the shutdown started
+pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Close code sent to the websocket clients when the server stops
+pub const GOING_AWAY_CLOSE_CODE: u16 = 1001;
+
+// Resolve on SIGINT or SIGTERM
+pub async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = signal::ctrl_c().await {
+            LogFile::add_log(LogLevel::Error, &format!("Failed to listen for SIGINT: {}", e)).ok();
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match signal::unix::signal(signal::unix::SignalKind::terminate()) {
+            Ok(mut stream) => {
+                stream.recv().await;
+            },
+            Err(e) => {
+                LogFile::add_log(LogLevel::Error, &format!("Failed to listen for SIGTERM: {}", e)).ok();
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => LogFile::add_log(LogLevel::Info, "Received SIGINT, shutting down").ok(),
+        _ = terminate => LogFile::add_log(LogLevel::Info, "Received SIGTERM, shutting down").ok(),
+    };
+}
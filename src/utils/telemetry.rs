@@ -0,0 +1,177 @@
+use tower_http::{
+    classify::{ServerErrorsAsFailures, SharedClassifier},
+    trace::{DefaultMakeSpan, DefaultOnRequest, DefaultOnResponse, TraceLayer}
+};
+use tracing::Level;
+use tracing_subscriber::{
+    EnvFilter,
+    fmt::{self, format::FmtSpan},
+    layer::SubscriberExt,
+    util::SubscriberInitExt
+};
+
+#[cfg(feature = "otlp")]
+use common::utils::log::{LogFile, LogLevel};
+#[cfg(feature = "otlp")]
+use opentelemetry::trace::TracerProvider;
+#[cfg(feature = "otlp")]
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+#[cfg(feature = "otlp")]
+use opentelemetry_sdk::{trace::SdkTracerProvider, Resource};
+
+// Read by the OTLP exporter, the spans are only exported when it is set
+#[cfg(feature = "otlp")]
+const OTLP_ENDPOINT_ENV: &str = "OTEL_EXPORTER_OTLP_ENDPOINT";
+
+// Keeps the exporter alive until shutdown
+pub struct Telemetry {
+    #[cfg(feature = "otlp")]
+    provider: Option<SdkTracerProvider>,
+}
+
+// Log every event and span as a JSON line on stdout
+// The level is set with RUST_LOG and defaults to info
+// With the otlp feature the spans are exported as well
+pub fn init_tracing() -> Result<Telemetry, Box<dyn std::error::Error>> {
+    let filter = EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| EnvFilter::new("info"));
+
+    // Closing a span logs its duration
+    let json = fmt::layer()
+        .json()
+        .with_current_span(true)
+        .with_span_events(FmtSpan::CLOSE);
+
+    let registry = tracing_subscriber::registry()
+        .with(filter)
+        .with(json);
+
+    #[cfg(feature = "otlp")]
+    let (registry, provider) = {
+        let provider = otlp_provider()?;
+        let layer = provider.as_ref()
+            .map(|provider| tracing_opentelemetry::layer().with_tracer(provider.tracer("server")));
+
+        (registry.with(layer), provider)
+    };
+
+    registry.try_init()?;
+
+    Ok(Telemetry {
+        #[cfg(feature = "otlp")]
+        provider,
+    })
+}
+
+impl Telemetry {
+    // Flush the spans not exported yet
+    pub fn shutdown(self) {
+        #[cfg(feature = "otlp")]
+        if let Some(Err(e)) = self.provider.map(|provider| provider.shutdown()) {
+            LogFile::add_log(LogLevel::Error, &format!("Failed to flush the OTLP exporter: {}", e)).ok();
+        }
+    }
+}
+
+// One span per HTTP request, with its method, uri and status
+pub fn http_trace_layer() -> TraceLayer<SharedClassifier<ServerErrorsAsFailures>> {
+    TraceLayer::new_for_http()
+        .make_span_with(DefaultMakeSpan::new().level(Level::INFO))
+        .on_request(DefaultOnRequest::new().level(Level::DEBUG))
+        .on_response(DefaultOnResponse::new().level(Level::INFO))
+}
+
+#[cfg(feature = "otlp")]
+fn otlp_provider() -> Result<Option<SdkTracerProvider>, Box<dyn std::error::Error>> {
+    let Ok(endpoint) = std::env::var(OTLP_ENDPOINT_ENV) else {
+        return Ok(None);
+    };
+
+    otlp_exporter_provider(endpoint).map(Some)
+}
+
+// Batch the spans and send them over gRPC to the collector at the endpoint
+#[cfg(feature = "otlp")]
+fn otlp_exporter_provider(endpoint: String) -> Result<SdkTracerProvider, Box<dyn std::error::Error>> {
+    let exporter = SpanExporter::builder()
+        .with_tonic()
+        .with_endpoint(endpoint)
+        .build()?;
+
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(Resource::builder().with_service_name("paragon-server").build())
+        .build();
+
+    Ok(provider)
+}
+
+#[cfg(all(test, feature = "otlp"))]
+mod tests {
+    use super::otlp_exporter_provider;
+
+    use opentelemetry::trace::TracerProvider;
+    use opentelemetry_proto::tonic::collector::trace::v1::{
+        trace_service_server::{TraceService, TraceServiceServer},
+        ExportTraceServiceRequest,
+        ExportTraceServiceResponse
+    };
+    use std::time::Duration;
+    use tokio::{
+        net::TcpListener,
+        sync::mpsc,
+        task::spawn_blocking,
+        time::timeout
+    };
+    use tokio_stream::wrappers::TcpListenerStream;
+    use tonic::{transport::Server, Request, Response, Status};
+    use tracing_subscriber::layer::SubscriberExt;
+
+    // Collector forwarding every export request to the test
+    struct MockCollector {
+        requests: mpsc::UnboundedSender<ExportTraceServiceRequest>,
+    }
+
+    #[tonic::async_trait]
+    impl TraceService for MockCollector {
+        async fn export(&self, request: Request<ExportTraceServiceRequest>) -> Result<Response<ExportTraceServiceResponse>, Status> {
+            self.requests.send(request.into_inner()).ok();
+
+            Ok(Response::new(ExportTraceServiceResponse { partial_success: None }))
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn exports_spans_to_the_collector() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let (requests, mut received) = mpsc::unbounded_channel();
+
+        tokio::spawn(Server::builder()
+            .add_service(TraceServiceServer::new(MockCollector { requests }))
+            .serve_with_incoming(TcpListenerStream::new(listener)));
+
+        let provider = otlp_exporter_provider(format!("http://{}", address)).unwrap();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("server")));
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info_span!("db.insert", table = "candles").in_scope(|| {});
+        });
+
+        // The batch exporter blocks while flushing
+        spawn_blocking(move || provider.shutdown()).await.unwrap().unwrap();
+
+        let request = timeout(Duration::from_secs(5), received.recv()).await
+            .expect("Nothing exported")
+            .unwrap();
+
+        let spans: Vec<&str> = request.resource_spans.iter()
+            .flat_map(|resource| &resource.scope_spans)
+            .flat_map(|scope| &scope.spans)
+            .map(|span| span.name.as_str())
+            .collect();
+
+        assert_eq!(spans, vec!["db.insert"]);
+    }
+}
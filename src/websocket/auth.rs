@@ -12,6 +12,9 @@ use jsonwebtoken::errors::{
     ErrorKind
 };
 
+// Senders without a publisher claim share the default slot
+pub const DEFAULT_PUBLISHER: &str = "default";
+
 pub fn auth_access(claim: Claim) -> Result<ClientRole, Error> {
     if let Some(role) = claim.role {
         match role.as_str() {
@@ -44,9 +47,16 @@ where S: Send + Sync {
         let token_data = verify_jwt(token)
             .map_err(|_| (StatusCode::UNAUTHORIZED, "Invalid token"))?;
 
+        let publisher = token_data.claims.publisher.clone();
+
         let role = auth_access(token_data.claims)
             .map_err(|_| (StatusCode::FORBIDDEN, "Permission denied"))?;
 
-        Ok(Role(role))
+        let publisher = match role {
+            ClientRole::Sender => Some(publisher.unwrap_or_else(|| DEFAULT_PUBLISHER.to_string())),
+            ClientRole::Receiver => None,
+        };
+
+        Ok(Role(role, publisher))
     }
 }
\ No newline at end of file
@@ -1,103 +1,270 @@
-use crate::websocket::structures::{ClientRole, Clients, Role};
+use crate::{
+    database::structures::{Permission, PermissionLevel},
+    metrics::structures::Metrics,
+    utils::shutdown::GOING_AWAY_CLOSE_CODE,
+    websocket::{
+        publishers::{claim_publisher, publisher_states, record_publication, release_publisher, PUBLISHER_TAKEN_CLOSE_CODE},
+        snapshot::stream_snapshot,
+        protocol::{decode_envelope, parse_envelope, validate_publication, SUBPROTOCOLS},
+        structures::{ClientQueue, ClientRole, Clients, ClientStats, ConnectParams, Control, Encoding, Envelope, ErrorCode, Frame, ProtocolError, PublisherState, Role, Topic, WebsocketState}
+    }
+};
 
 use axum::{
     Extension,
-    extract::ws::{
-        Message,
-        WebSocket,
-        WebSocketUpgrade
+    extract::{
+        Query,
+        ws::{
+            CloseFrame,
+            Message,
+            WebSocket,
+            WebSocketUpgrade
+        }
     },
+    http::StatusCode,
+    Json,
     response::IntoResponse,
 };
+use common::utils::log::{
+    LogFile,
+    LogLevel
+};
 use futures::{
     SinkExt,
     StreamExt
 };
-use std::sync::{Arc, Mutex};
+use std::{
+    sync::Arc,
+    time::Duration
+};
 use tokio::{
-    sync::mpsc::{unbounded_channel, UnboundedSender},
     select,
     task,
+    time::timeout
 };
+use tracing::{info_span, Instrument};
 use uuid::Uuid;
 
-static SENDER: Mutex<Option<UnboundedSender<Message>>> = Mutex::new(None);
+// Close codes of a receiver disconnected for a frame it sent
+pub const UNSUPPORTED_DATA_CLOSE_CODE: u16 = 1003;
+pub const POLICY_VIOLATION_CLOSE_CODE: u16 = 1008;
+
+// How long the error and close frames of a rejected receiver may take to be written
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+pub async fn websocket_handler(ws: WebSocketUpgrade, Extension(state): Extension<WebsocketState>, Query(params): Query<ConnectParams>, Role(role, publisher): Role) -> impl IntoResponse {
+    // The subprotocol wins over the query parameter, JSON is the default
+    let ws = ws.protocols(SUBPROTOCOLS);
+    let encoding = ws.selected_protocol()
+        .and_then(|protocol| protocol.to_str().ok())
+        .and_then(Encoding::from_protocol)
+        .or(params.encoding)
+        .unwrap_or_default();
+
+    // Everything logged by the connection carries the client and its role
+    let client_id = Uuid::new_v4();
+    let span = info_span!("websocket", client_id = %client_id, role = Metrics::role_label(&role), publisher = publisher.as_deref());
 
-pub async fn websocket_handler(ws: WebSocketUpgrade, Extension(clients): Extension<Clients>, role: Role) -> impl IntoResponse {
-    let role = role.0;
-    ws.on_upgrade(move |socket| handle_websocket(socket, role, clients))
+    ws.on_upgrade(move |socket| handle_websocket(socket, client_id, role, publisher, params, encoding, state).instrument(span))
 }
 
-pub async fn handle_websocket(socket: WebSocket, role: ClientRole, clients: Clients) {
-    let (tx, mut rx) = unbounded_channel::<Message>();
+// Queue statistics of every receiver, only for admins
+pub async fn clients_handler(Extension(state): Extension<WebsocketState>, Permission(permission): Permission) -> Result<Json<Vec<ClientStats>>, StatusCode> {
+    if permission != PermissionLevel::Admin {
+        return Err(StatusCode::FORBIDDEN);
+    }
 
-    let ping_tx = tx.clone();
+    let stats = state.clients.lock().unwrap().stats();
 
-    let client_id = Uuid::new_v4();
+    Ok(Json(stats))
+}
+
+// Connected publishers, only for admins
+pub async fn publishers_handler(Extension(state): Extension<WebsocketState>, Permission(permission): Permission) -> Result<Json<Vec<PublisherState>>, StatusCode> {
+    if permission != PermissionLevel::Admin {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    Ok(Json(publisher_states(&state.publishers)))
+}
+
+pub async fn handle_websocket(mut socket: WebSocket, client_id: Uuid, role: ClientRole, publisher: Option<String>, params: ConnectParams, encoding: Encoding, state: WebsocketState) {
+    let WebsocketState { clients, publishers, settings, pool, persister, shutdown } = state;
 
-    let role_clone = role.clone();
+    let queue = Arc::new(ClientQueue::new(settings.queue, encoding));
+
+    let ping_queue = Arc::clone(&queue);
+    let send_queue = Arc::clone(&queue);
+    let closing_queue = Arc::clone(&queue);
 
     if role == ClientRole::Receiver {
-        clients.lock().unwrap().insert(client_id, tx.clone());
-    } else {
-        if let Some(_) = SENDER.lock().unwrap().as_ref() {
-            // We don't allow multiple senders
-            // The only sender is our algorithm
-            // So we can refuse the new one
+        clients.lock().unwrap().register(client_id, Arc::clone(&queue));
+    } else if let Some(name) = &publisher {
+        // Each publisher name has a single slot
+        // A second sender is refused unless it asks to take over
+        if !claim_publisher(&publishers, name, client_id, Arc::clone(&queue), params.takeover) {
+            LogFile::add_log(LogLevel::Error, &format!("Refused sender {}: publisher {} is already connected", client_id, name)).ok();
+
+            socket.send(Message::Close(Some(CloseFrame {
+                code: PUBLISHER_TAKEN_CLOSE_CODE,
+                reason: format!("Publisher {} is already connected", name).into(),
+            }))).await.ok();
 
             return;
-        } else {
-            SENDER.lock().unwrap().replace(tx.clone());
         }
     }
 
+    // The shutdown may have started while this client was registering
+    // After it has been registered, so the close can't be missed
+    if shutdown.is_cancelled() {
+        queue.close_after_flush(GOING_AWAY_CLOSE_CODE, "Server shutting down");
+    }
+
+    let metrics = Metrics::global();
+    let role_label = Metrics::role_label(&role);
+
+    metrics.websocket_connections.with_label_values(&[role_label]).inc();
+    metrics.websocket_connections_total.with_label_values(&[role_label]).inc();
+
     let (mut ws_sender, mut ws_receiver) = socket.split();
 
     let receiving_clients = Arc::clone(&clients);
+    let receiving_publishers = Arc::clone(&publishers);
+    let publisher_name = publisher.clone();
 
     let mut send_task = task::spawn(async move {
-        while let Some(message) = rx.recv().await {
-            if ws_sender.send(message.clone()).await.is_err() {
+        while let Some(message) = send_queue.pop().await {
+            let closing = matches!(message, Message::Close(_));
+
+            if ws_sender.send(message).await.is_err() {
+                break;
+            }
+
+            metrics.websocket_messages_out.inc();
+
+            if closing {
                 break;
             }
         }
-    });
+    }.in_current_span());
 
     let mut receive_task = task::spawn(async move {
         while let Some(Ok(message)) = ws_receiver.next().await {
-            match message {
+            let parsed = match message {
                 Message::Ping(payload) => {
-                    if let Err(e) = tx.send(Message::Pong(payload)) {
-                        eprintln!("Failed to send pong response: {:?}", e);
+                    let queued = queue.push(None, Message::Pong(payload));
+
+                    if !queued {
+                        eprintln!("Failed to send pong response: queue closed");
                         break;
                     }
+
+                    continue;
+                },
+                // Text frames are always JSON
+                Message::Text(text) => parse_envelope(&text),
+                Message::Binary(bytes) => decode_envelope(queue.encoding, &bytes),
+                Message::Close(_) => {
+                    break;
                 },
-                Message::Text(text) => {
+                _ => continue,
+            };
+
+            metrics.websocket_messages_in.with_label_values(&[role_label]).inc();
+
+            let envelope = match parsed {
+                Ok(envelope) => envelope,
+                Err(error) => {
+                    LogFile::add_log(LogLevel::Error, &format!("Rejected frame from {}: {}", client_id, error.message)).ok();
+
+                    reply(&queue, &Envelope::error(error));
+
                     if role == ClientRole::Receiver {
-                        // If the client is a receiver
-                        // We kick him out to avoid overloading the server
+                        queue.close_after_flush(UNSUPPORTED_DATA_CLOSE_CODE, "Invalid frame");
                         break;
                     }
-                    
-                    send_message_to_all_clients(&receiving_clients, Message::Text(text)).await;
+
+                    continue;
+                }
+            };
+
+            if role == ClientRole::Receiver {
+                // A receiver may only send control frames
+                // Anything else closes the connection to avoid overloading the server
+                match envelope.frame {
+                    Frame::Control(Control::Subscribe { topic, last_seq, stream }) => {
+                        let resumed = receiving_clients.lock().unwrap().subscribe(client_id, topic.clone(), stream, last_seq, &queue);
+
+                        if resumed {
+                            continue;
+                        }
+
+                        // Replay the latest rows before going live
+                        task::spawn(stream_snapshot(
+                            Arc::clone(&receiving_clients),
+                            Arc::clone(&pool),
+                            settings.snapshot,
+                            client_id,
+                            Arc::clone(&queue),
+                            topic
+                        ).in_current_span());
+                    },
+                    Frame::Control(Control::Unsubscribe(topic)) => {
+                        receiving_clients.lock().unwrap().unsubscribe(&client_id, &topic);
+                    },
+                    Frame::Heartbeat { .. } => {},
+                    _ => {
+                        reply(&queue, &Envelope::error(ProtocolError::new(ErrorCode::UnexpectedFrame, "Receivers can only send control frames".to_string())));
+                        queue.close_after_flush(POLICY_VIOLATION_CLOSE_CODE, "Unexpected frame");
+                        break;
+                    },
+                }
+
+                continue;
+            }
+
+            if let Frame::Heartbeat { .. } = envelope.frame {
+                continue;
+            }
+
+            // Every entity frame carries its topic
+            // So we only forward it to the matching subscribers
+            match validate_publication(&envelope) {
+                Ok(topic) => {
+                    if let Some(name) = &publisher_name {
+                        record_publication(&receiving_publishers, name, client_id);
+                    }
+
+                    // Waits when the persister is behind
+                    // So a sender can't outrun the database
+                    if let Some(persister) = &persister {
+                        let sent = persister.send(envelope.frame.clone()).await;
+
+                        if sent.is_err() {
+                            LogFile::add_log(LogLevel::Error, &format!("Failed to persist frame from {}: persister stopped", client_id)).ok();
+                        }
+                    }
+
+                    send_message_to_subscribers(&receiving_clients, &topic, envelope).await;
                 },
-                Message::Close(_) => {
-                    break;
+                Err(error) => {
+                    LogFile::add_log(LogLevel::Error, &format!("Rejected frame from {}: {}", client_id, error.message)).ok();
+
+                    reply(&queue, &Envelope::error(error));
                 },
-                _ => {}
             }
         }
-    });
+    }.in_current_span());
 
     let mut ping_task = task::spawn(async move {
         loop {
             tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
-            if ping_tx.send(Message::Ping(Vec::new().into())).is_err() {
+            if !ping_queue.push(None, Message::Ping(Vec::new().into())) {
                 break;
             }
         }
 
-    });
+    }.in_current_span());
 
     select! {
         _ = &mut send_task => {
@@ -105,33 +272,46 @@ pub async fn handle_websocket(socket: WebSocket, role: ClientRole, clients: Clie
             ping_task.abort();
         },
         _ = &mut receive_task => {
-            send_task.abort();
             ping_task.abort();
+
+            // A rejected receiver has its error and close frames queued, let them be written
+            if closing_queue.is_closed() {
+                timeout(CLOSE_TIMEOUT, &mut send_task).await.ok();
+            }
+
+            send_task.abort();
         },
         _ = &mut ping_task => {
-            send_task.abort();
+            // The queue was closed, let its close frame be written
             receive_task.abort();
+            (&mut send_task).await.ok();
         },
     }
 
-    if role_clone == ClientRole::Sender {
-        let mut sender_lock = SENDER.lock().unwrap();
-        sender_lock.take();
-    } else {
-        clients.lock().unwrap().remove(&client_id);
+    match &publisher {
+        Some(name) => release_publisher(&publishers, name, client_id),
+        None => clients.lock().unwrap().unregister(&client_id),
     }
+
+    metrics.websocket_connections.with_label_values(&[role_label]).dec();
 }
 
-pub async fn send_message_to_all_clients(clients: &Clients, message: Message) {
-    let connected_clients: Vec<UnboundedSender<Message>> = {
-        let guard = clients.lock().unwrap();
+pub async fn send_message_to_subscribers(clients: &Clients, topic: &Topic, envelope: Envelope) {
+    let subscribers = {
+        let mut guard = clients.lock().unwrap();
 
-        guard.values().cloned().collect()
+        guard.route(topic, envelope)
     };
 
-    for tx in connected_clients {
-        if let Err(e) = tx.send(message.clone()) {
-            eprintln!("Failed to send message to a client: {:?}", e);
-        }
+    // Each queue applies its own overflow policy
+    // So a slow client never blocks the others
+    // A closed queue ignores the message, its client is about to be unregistered
+    for (queue, message) in subscribers {
+        queue.push(Some(topic), message);
     }
-}
\ No newline at end of file
+}
+
+// Send a frame back to the client that caused it
+fn reply(queue: &ClientQueue, envelope: &Envelope) {
+    queue.push_envelope(None, envelope);
+}
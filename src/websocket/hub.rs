@@ -0,0 +1,304 @@
+use crate::websocket::structures::{ClientQueue, ClientStats, Control, Encoding, Envelope, Hub, Topic, TopicHistory};
+
+use axum::extract::ws::Message;
+use common::utils::log::{
+    LogFile,
+    LogLevel
+};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Arc
+};
+use uuid::Uuid;
+
+impl Hub {
+    pub fn new(history_capacity: usize) -> Self {
+        Hub {
+            stream: Uuid::new_v4(),
+            clients: HashMap::new(),
+            topics: HashMap::new(),
+            pending: HashMap::new(),
+            history: HashMap::new(),
+            history_capacity,
+        }
+    }
+
+    pub fn register(&mut self, client_id: Uuid, queue: Arc<ClientQueue>) {
+        self.clients.insert(client_id, queue);
+    }
+
+    pub fn unregister(&mut self, client_id: &Uuid) {
+        self.clients.remove(client_id);
+
+        // Drop the client from every topic and forget the empty ones
+        self.topics.retain(|_, subscribers| {
+            subscribers.remove(client_id);
+            !subscribers.is_empty()
+        });
+        self.pending.retain(|(pending_id, _), _| pending_id != client_id);
+    }
+
+    // A resuming client gets the messages it missed and goes live directly
+    // Otherwise the subscription starts on hold
+    // And live messages are kept aside until the snapshot is sent
+    // Returns true when the client was resumed
+    pub fn subscribe(&mut self, client_id: Uuid, topic: Topic, stream: Option<Uuid>, last_seq: Option<u64>, queue: &ClientQueue) -> bool {
+        if let Some(last_seq) = last_seq {
+            match self.missed_messages(&topic, stream, last_seq) {
+                Some(missed) => {
+                    // Still under the hub lock, so no message can come in between
+                    for envelope in missed {
+                        queue.push_envelope(Some(&topic), &envelope);
+                    }
+
+                    self.topics.entry(topic).or_default().insert(client_id);
+
+                    return true;
+                },
+                None => {
+                    queue.push_envelope(None, &Envelope::control(Control::Resync(topic.clone())));
+                },
+            }
+        }
+
+        self.pending.insert((client_id, topic.clone()), Vec::new());
+        self.topics.entry(topic).or_default().insert(client_id);
+
+        false
+    }
+
+    // Messages of a topic sent after last_seq
+    // None when some of them are no longer retained
+    pub fn missed_messages(&self, topic: &Topic, stream: Option<Uuid>, last_seq: u64) -> Option<Vec<Envelope>> {
+        // The sequence numbers come from another hub, the server restarted meanwhile
+        if stream != Some(self.stream) {
+            return None;
+        }
+
+        let Some(history) = self.history.get(topic) else {
+            // Nothing was sent on this topic yet
+            return (last_seq == 0).then(Vec::new);
+        };
+
+        // The client knows messages we never sent
+        if last_seq >= history.next_seq {
+            return None;
+        }
+
+        let oldest = history.messages.front()
+            .map(|(seq, _)| *seq)
+            .unwrap_or(history.next_seq);
+
+        if oldest > last_seq + 1 {
+            return None;
+        }
+
+        Some(history.messages.iter()
+            .filter(|(seq, _)| *seq > last_seq)
+            .map(|(_, envelope)| envelope.clone())
+            .collect())
+    }
+
+    // Switch a subscription from snapshot to live
+    // Returns the held messages, or None if the client unsubscribed meanwhile
+    pub fn activate(&mut self, client_id: Uuid, topic: Topic) -> Option<Vec<Envelope>> {
+        self.pending.remove(&(client_id, topic))
+    }
+
+    pub fn unsubscribe(&mut self, client_id: &Uuid, topic: &Topic) {
+        self.pending.remove(&(*client_id, topic.clone()));
+
+        if let Some(subscribers) = self.topics.get_mut(topic) {
+            subscribers.remove(client_id);
+
+            if subscribers.is_empty() {
+                self.topics.remove(topic);
+            }
+        }
+    }
+
+    // Stamp the envelope with the next sequence number of its topic and keep it in the history
+    // Returns the live subscribers with the message in their encoding
+    // Each encoding is only computed once
+    // And holds the envelope for the subscribers still receiving their snapshot
+    pub fn route(&mut self, topic: &Topic, mut envelope: Envelope) -> Vec<(Arc<ClientQueue>, Message)> {
+        let history = self.history.entry(topic.clone())
+            .or_insert_with(|| TopicHistory {
+                next_seq: 1,
+                messages: VecDeque::new(),
+            });
+
+        let seq = history.next_seq;
+        envelope.seq = Some(seq);
+        envelope.stream = Some(self.stream);
+
+        history.next_seq += 1;
+        history.messages.push_back((seq, envelope.clone()));
+
+        while history.messages.len() > self.history_capacity {
+            history.messages.pop_front();
+        }
+
+        let Some(subscribers) = self.topics.get(topic) else {
+            return Vec::new();
+        };
+
+        let mut encoded: HashMap<Encoding, Message> = HashMap::new();
+        let mut live = Vec::with_capacity(subscribers.len());
+
+        for client_id in subscribers {
+            if let Some(held) = self.pending.get_mut(&(*client_id, topic.clone())) {
+                held.push(envelope.clone());
+            } else if let Some(queue) = self.clients.get(client_id) {
+                let message = match encoded.get(&queue.encoding) {
+                    Some(message) => message.clone(),
+                    None => match envelope.encode(queue.encoding) {
+                        Ok(message) => {
+                            encoded.insert(queue.encoding, message.clone());
+
+                            message
+                        },
+                        Err(e) => {
+                            LogFile::add_log(LogLevel::Error, &format!("Failed to encode frame as {:?}: {}", queue.encoding, e)).ok();
+
+                            continue;
+                        },
+                    },
+                };
+
+                live.push((Arc::clone(queue), message));
+            }
+        }
+
+        live
+    }
+
+    // Close every receiver once its queue is flushed
+    pub fn close_all(&self, code: u16, reason: &str) {
+        for queue in self.clients.values() {
+            queue.close_after_flush(code, reason);
+        }
+    }
+
+    pub fn stats(&self) -> Vec<ClientStats> {
+        self.clients.iter()
+            .map(|(client_id, queue)| ClientStats {
+                id: *client_id,
+                queued: queue.len(),
+                dropped: queue.dropped(),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::websocket::{
+        protocol::parse_envelope,
+        structures::{ClientQueue, Control, Encoding, EntityKind, Envelope, Frame, Hub, QueueSettings, Topic}
+    };
+
+    use std::sync::Arc;
+    use uuid::Uuid;
+
+    fn topic() -> Topic {
+        Topic {
+            symbol: "BTC".to_string(),
+            timerange: Some("1m".to_string()),
+            kind: EntityKind::Candle,
+        }
+    }
+
+    // A hub that routed count messages on the topic
+    fn hub(history: usize, count: usize) -> Hub {
+        let mut hub = Hub::new(history);
+
+        for _ in 0..count {
+            hub.route(&topic(), Envelope::heartbeat());
+        }
+
+        hub
+    }
+
+    fn seqs(missed: Option<Vec<Envelope>>) -> Option<Vec<u64>> {
+        missed.map(|envelopes| envelopes.iter().filter_map(|envelope| envelope.seq).collect())
+    }
+
+    #[test]
+    fn stamps_the_sequence_and_the_stream() {
+        let mut hub = Hub::new(8);
+        let stream = hub.stream;
+
+        hub.route(&topic(), Envelope::heartbeat());
+        hub.route(&topic(), Envelope::heartbeat());
+
+        let (seq, envelope) = hub.history[&topic()].messages.back().unwrap();
+
+        assert_eq!(*seq, 2);
+        assert_eq!(envelope.seq, Some(2));
+        assert_eq!(envelope.stream, Some(stream));
+    }
+
+    #[test]
+    fn replays_the_messages_in_the_window() {
+        let hub = hub(8, 4);
+
+        assert_eq!(seqs(hub.missed_messages(&topic(), Some(hub.stream), 1)), Some(vec![2, 3, 4]));
+        assert_eq!(seqs(hub.missed_messages(&topic(), Some(hub.stream), 4)), Some(vec![]));
+    }
+
+    #[test]
+    fn resyncs_once_the_messages_are_evicted() {
+        let hub = hub(2, 4);
+
+        assert_eq!(seqs(hub.missed_messages(&topic(), Some(hub.stream), 1)), None);
+        assert_eq!(seqs(hub.missed_messages(&topic(), Some(hub.stream), 2)), Some(vec![3, 4]));
+    }
+
+    #[test]
+    fn resyncs_on_a_future_sequence() {
+        let hub = hub(8, 4);
+
+        assert_eq!(seqs(hub.missed_messages(&topic(), Some(hub.stream), 5)), None);
+    }
+
+    #[test]
+    fn resyncs_on_another_stream() {
+        // The server restarted, the new hub is already past the client's last_seq
+        let old = hub(8, 2);
+        let new = hub(8, 5);
+
+        assert_eq!(seqs(new.missed_messages(&topic(), Some(old.stream), 2)), None);
+        assert_eq!(seqs(new.missed_messages(&topic(), None, 2)), None);
+    }
+
+    #[test]
+    fn resync_puts_the_subscription_on_hold() {
+        let mut hub = hub(8, 2);
+        let client_id = Uuid::new_v4();
+        let queue = ClientQueue::new(QueueSettings::default(), Encoding::Json);
+
+        assert!(!hub.subscribe(client_id, topic(), Some(Uuid::new_v4()), Some(1), &queue));
+        assert!(hub.pending.contains_key(&(client_id, topic())));
+
+        let queued = queue.buffer.lock().unwrap().pop_front().unwrap().message;
+        let resync = parse_envelope(queued.to_text().unwrap()).unwrap();
+        assert!(matches!(resync.frame, Frame::Control(Control::Resync(_))));
+
+        // Live messages wait for the snapshot
+        hub.register(client_id, Arc::new(queue));
+        assert!(hub.route(&topic(), Envelope::heartbeat()).is_empty());
+        assert_eq!(hub.pending[&(client_id, topic())].len(), 1);
+    }
+
+    #[test]
+    fn resume_goes_live_with_the_missed_messages() {
+        let mut hub = hub(8, 3);
+        let client_id = Uuid::new_v4();
+        let queue = ClientQueue::new(QueueSettings::default(), Encoding::Json);
+
+        assert!(hub.subscribe(client_id, topic(), Some(hub.stream), Some(1), &queue));
+        assert!(!hub.pending.contains_key(&(client_id, topic())));
+        assert_eq!(queue.len(), 2);
+    }
+}
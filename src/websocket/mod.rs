@@ -1,4 +1,10 @@
 pub mod handler;
+pub mod hub;
 pub mod auth;
+pub mod persistence;
+pub mod protocol;
+pub mod publishers;
+pub mod queue;
+pub mod snapshot;
 pub mod structures;
 pub mod websocket;
\ No newline at end of file
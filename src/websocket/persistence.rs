@@ -0,0 +1,170 @@
+use crate::{
+    database::{
+        database::InsertTarget,
+        structures::RetryPolicy,
+        graphql::{
+            mutation::{
+                insert_candles,
+                insert_one_d_structures,
+                insert_sessions,
+                insert_trends,
+                insert_two_d_structures
+            },
+            upsert::ConflictMode
+        }
+    },
+    websocket::structures::{Frame, PendingBatch, PersistenceSettings}
+};
+
+use common::{
+    entities::{
+        candle::CandleInput,
+        session::SessionInput,
+        structures::{OneDStructuresInput, TwoDStructuresInput},
+        trend::TrendInput
+    },
+    utils::log::{LogFile, LogLevel}
+};
+use sqlx::PgPool;
+use std::sync::Arc;
+use tokio::{
+    select,
+    sync::mpsc::Receiver,
+    time::{interval, MissedTickBehavior},
+};
+use tokio_util::sync::CancellationToken;
+
+// Batch the frames of the senders and write them with the GraphQL insert functions
+// So the live feed and the database hold the same rows
+// Returns once every sender handle is dropped, or stop is cancelled, and the last batch is written
+pub async fn run_persister(pool: Arc<PgPool>, settings: PersistenceSettings, mut frames: Receiver<Frame>, stop: CancellationToken) {
+    let mut batch = PendingBatch::default();
+
+    let mut ticker = interval(settings.flush_interval);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    loop {
+        select! {
+            frame = frames.recv() => match frame {
+                Some(frame) => {
+                    batch.push(frame);
+
+                    if batch.len() >= settings.batch_size {
+                        flush(&pool, settings.retry, &mut batch).await;
+                    }
+                },
+                None => {
+                    flush(&pool, settings.retry, &mut batch).await;
+                    break;
+                },
+            },
+            _ = ticker.tick() => {
+                flush(&pool, settings.retry, &mut batch).await;
+            },
+            // Refuse new frames but keep the buffered ones
+            // recv returns None once they are all read
+            _ = stop.cancelled(), if !frames.is_closed() => {
+                frames.close();
+            },
+        }
+    }
+}
+
+async fn flush(pool: &Arc<PgPool>, retry: RetryPolicy, batch: &mut PendingBatch) {
+    if batch.is_empty() {
+        return;
+    }
+
+    let PendingBatch { candles, sessions, trends, one_d_structures, two_d_structures } = std::mem::take(batch);
+
+    // The insert functions already retry and log their failures
+    // A live candle is sent again as it forms, so the last frame wins
+    let (candle_res, session_res, trend_res, one_d_res, two_d_res) = tokio::join!(
+        insert_candles(InsertTarget::Pool(Arc::clone(pool), retry), &candles, ConflictMode::Overwrite),
+        insert_sessions(InsertTarget::Pool(Arc::clone(pool), retry), &sessions, ConflictMode::Overwrite),
+        insert_trends(InsertTarget::Pool(Arc::clone(pool), retry), &trends, ConflictMode::Overwrite),
+        insert_one_d_structures(InsertTarget::Pool(Arc::clone(pool), retry), &one_d_structures, ConflictMode::Overwrite),
+        insert_two_d_structures(InsertTarget::Pool(Arc::clone(pool), retry), &two_d_structures, ConflictMode::Overwrite),
+    );
+
+    for (name, failed, count) in [
+        ("candles", candle_res.is_failed(), candles.len()),
+        ("sessions", session_res.is_failed(), sessions.len()),
+        ("trends", trend_res.is_failed(), trends.len()),
+        ("one_d_structures", one_d_res.is_failed(), one_d_structures.len()),
+        ("two_d_structures", two_d_res.is_failed(), two_d_structures.len()),
+    ] {
+        if failed {
+            LogFile::add_log(LogLevel::Error, &format!("Lost {} {} from the websocket feed", count, name)).ok();
+        }
+    }
+}
+
+impl PendingBatch {
+    pub fn push(&mut self, frame: Frame) {
+        match frame {
+            Frame::Candle(candle) => self.candles.push(CandleInput {
+                symbol: candle.symbol,
+                timerange: candle.timerange,
+                timestamp: candle.timestamp,
+                open: candle.open,
+                high: candle.high,
+                low: candle.low,
+                close: candle.close,
+                volume: candle.volume,
+                direction: candle.direction,
+            }),
+            Frame::Session(session) => self.sessions.push(SessionInput {
+                symbol: session.symbol,
+                label: session.label,
+                start_time: session.start_time,
+                end_time: session.end_time,
+                high: session.high,
+                low: session.low,
+                open: session.open,
+                close: session.close,
+                volume: session.volume,
+            }),
+            Frame::Trend(trend) => self.trends.push(TrendInput {
+                symbol: trend.symbol,
+                timerange: trend.timerange,
+                start_time: trend.start_time,
+                end_time: trend.end_time,
+                direction: trend.direction,
+                high: trend.high,
+                low: trend.low,
+            }),
+            Frame::OneDStructure(structure) => self.one_d_structures.push(OneDStructuresInput {
+                symbol: structure.symbol,
+                structure: structure.structure,
+                timerange: structure.timerange,
+                timestamp: structure.timestamp,
+                price: structure.price,
+                direction: structure.direction,
+            }),
+            Frame::TwoDStructure(structure) => self.two_d_structures.push(TwoDStructuresInput {
+                symbol: structure.symbol,
+                structure: structure.structure,
+                timerange: structure.timerange,
+                timestamp: structure.timestamp,
+                high: structure.high,
+                low: structure.low,
+                direction: structure.direction,
+            }),
+            // Only entities are stored
+            Frame::Heartbeat { .. } | Frame::Error { .. } | Frame::Control(_) => {},
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.candles.len()
+            + self.sessions.len()
+            + self.trends.len()
+            + self.one_d_structures.len()
+            + self.two_d_structures.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
@@ -0,0 +1,264 @@
+use crate::websocket::structures::{Control, Encoding, EntityKind, Envelope, ErrorCode, Frame, ProtocolError, Topic};
+
+use axum::extract::ws::Message;
+use chrono::{DateTime, Utc};
+
+pub const PROTOCOL_VERSION: u32 = 1;
+
+// Subprotocols a client can ask for in Sec-WebSocket-Protocol
+pub const SUBPROTOCOLS: [&str; 3] = ["paragon.json", "paragon.msgpack", "paragon.cbor"];
+
+impl Encoding {
+    pub fn from_protocol(protocol: &str) -> Option<Self> {
+        match protocol {
+            "paragon.json" => Some(Encoding::Json),
+            "paragon.msgpack" => Some(Encoding::MessagePack),
+            "paragon.cbor" => Some(Encoding::Cbor),
+            _ => None,
+        }
+    }
+}
+
+impl Envelope {
+    pub fn new(frame: Frame) -> Self {
+        Envelope {
+            version: PROTOCOL_VERSION,
+            seq: None,
+            stream: None,
+            frame,
+        }
+    }
+
+    pub fn error(error: ProtocolError) -> Self {
+        Envelope::new(Frame::Error {
+            code: error.code,
+            message: error.message,
+        })
+    }
+
+    pub fn control(control: Control) -> Self {
+        Envelope::new(Frame::Control(control))
+    }
+
+    pub fn heartbeat() -> Self {
+        Envelope::new(Frame::Heartbeat { timestamp: Utc::now() })
+    }
+
+    // JSON goes in text frames, the binary encodings in binary frames
+    pub fn encode(&self, encoding: Encoding) -> Result<Message, Box<dyn std::error::Error + Send + Sync>> {
+        match encoding {
+            Encoding::Json => Ok(Message::Text(serde_json::to_string(self)?.into())),
+            Encoding::MessagePack => Ok(Message::Binary(rmp_serde::to_vec_named(self)?.into())),
+            Encoding::Cbor => {
+                let mut bytes = Vec::new();
+                ciborium::into_writer(self, &mut bytes)?;
+
+                Ok(Message::Binary(bytes.into()))
+            },
+        }
+    }
+}
+
+impl ProtocolError {
+    pub fn new(code: ErrorCode, message: String) -> Self {
+        ProtocolError { code, message }
+    }
+}
+
+impl Frame {
+    // The topic of an entity frame, None for the other frames
+    pub fn topic(&self) -> Option<Topic> {
+        let (symbol, timerange, kind) = match self {
+            Frame::Candle(candle) => (&candle.symbol, Some(&candle.timerange), EntityKind::Candle),
+            Frame::Session(session) => (&session.symbol, None, EntityKind::Session),
+            Frame::Trend(trend) => (&trend.symbol, Some(&trend.timerange), EntityKind::Trend),
+            Frame::OneDStructure(structure) => (&structure.symbol, Some(&structure.timerange), EntityKind::OneDStructure),
+            Frame::TwoDStructure(structure) => (&structure.symbol, Some(&structure.timerange), EntityKind::TwoDStructure),
+            Frame::Heartbeat { .. } | Frame::Error { .. } | Frame::Control(_) => return None,
+        };
+
+        Some(Topic {
+            symbol: symbol.clone(),
+            timerange: timerange.cloned(),
+            kind,
+        })
+    }
+
+    // The natural key of an entity frame within its topic
+    // The symbol, timerange and kind are given by the topic
+    pub fn row_key(&self) -> Option<(String, DateTime<Utc>)> {
+        match self {
+            Frame::Candle(candle) => Some((String::new(), candle.timestamp)),
+            Frame::Session(session) => Some((session.label.clone(), session.start_time)),
+            Frame::Trend(trend) => Some((String::new(), trend.start_time)),
+            Frame::OneDStructure(structure) => Some((structure.structure.clone(), structure.timestamp)),
+            Frame::TwoDStructure(structure) => Some((structure.structure.clone(), structure.timestamp)),
+            Frame::Heartbeat { .. } | Frame::Error { .. } | Frame::Control(_) => None,
+        }
+    }
+}
+
+// Parse and check a frame received from a client
+pub fn parse_envelope(text: &str) -> Result<Envelope, ProtocolError> {
+    let envelope = serde_json::from_str::<Envelope>(text)
+        .map_err(|e| ProtocolError::new(ErrorCode::MalformedFrame, e.to_string()))?;
+
+    check_version(envelope)
+}
+
+// Same for a binary frame, in the encoding negotiated by the client
+pub fn decode_envelope(encoding: Encoding, bytes: &[u8]) -> Result<Envelope, ProtocolError> {
+    let envelope = match encoding {
+        Encoding::Json => serde_json::from_slice::<Envelope>(bytes)
+            .map_err(|e| e.to_string()),
+        Encoding::MessagePack => rmp_serde::from_slice::<Envelope>(bytes)
+            .map_err(|e| e.to_string()),
+        Encoding::Cbor => ciborium::from_reader::<Envelope, _>(bytes)
+            .map_err(|e| e.to_string()),
+    };
+
+    let envelope = envelope
+        .map_err(|e| ProtocolError::new(ErrorCode::MalformedFrame, e))?;
+
+    check_version(envelope)
+}
+
+fn check_version(envelope: Envelope) -> Result<Envelope, ProtocolError> {
+    if envelope.version != PROTOCOL_VERSION {
+        return Err(ProtocolError::new(
+            ErrorCode::UnsupportedVersion,
+            format!("Unsupported protocol version {}, expected {}", envelope.version, PROTOCOL_VERSION)
+        ));
+    }
+
+    Ok(envelope)
+}
+
+// Check an entity frame published by a sender
+// So a malformed payload never reaches the receivers
+pub fn validate_publication(envelope: &Envelope) -> Result<Topic, ProtocolError> {
+    let Some(topic) = envelope.frame.topic() else {
+        return Err(ProtocolError::new(ErrorCode::UnexpectedFrame, "Senders can only publish entity frames".to_string()));
+    };
+
+    if topic.symbol.is_empty() {
+        return Err(ProtocolError::new(ErrorCode::InvalidEntity, "Missing symbol".to_string()));
+    }
+
+    if topic.timerange.as_ref().is_some_and(|timerange| timerange.is_empty()) {
+        return Err(ProtocolError::new(ErrorCode::InvalidEntity, "Missing timerange".to_string()));
+    }
+
+    let values = match &envelope.frame {
+        Frame::Candle(candle) => vec![candle.open, candle.high, candle.low, candle.close, candle.volume],
+        Frame::Session(session) => vec![session.open, session.high, session.low, session.close, session.volume],
+        Frame::Trend(trend) => vec![trend.high, trend.low],
+        Frame::OneDStructure(structure) => vec![structure.price],
+        Frame::TwoDStructure(structure) => vec![structure.high, structure.low],
+        _ => Vec::new(),
+    };
+
+    if values.iter().any(|value| !value.is_finite()) {
+        return Err(ProtocolError::new(ErrorCode::InvalidEntity, "Prices and volumes must be finite numbers".to_string()));
+    }
+
+    let range = match &envelope.frame {
+        Frame::Candle(candle) => Some((candle.high, candle.low)),
+        Frame::Session(session) => Some((session.high, session.low)),
+        Frame::Trend(trend) => Some((trend.high, trend.low)),
+        Frame::TwoDStructure(structure) => Some((structure.high, structure.low)),
+        _ => None,
+    };
+
+    if let Some((high, low)) = range.filter(|(high, low)| high < low) {
+        return Err(ProtocolError::new(ErrorCode::InvalidEntity, format!("High {} is below low {}", high, low)));
+    }
+
+    Ok(topic)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_envelope, parse_envelope, validate_publication, PROTOCOL_VERSION};
+    use crate::{
+        websocket::structures::{Control, Encoding, EntityKind, Envelope, ErrorCode, Frame, Topic},
+        Candle
+    };
+
+    use axum::extract::ws::Message;
+    use chrono::{TimeZone, Utc};
+    use uuid::Uuid;
+
+    fn candle(high: f64, low: f64) -> Envelope {
+        Envelope::new(Frame::Candle(Candle {
+            symbol: "BTC".to_string(),
+            timerange: "1m".to_string(),
+            timestamp: Utc.with_ymd_and_hms(2024, 1, 1, 8, 0, 0).unwrap(),
+            open: 1.0,
+            high,
+            low,
+            close: 2.0,
+            volume: 10.0,
+            direction: "Bullish".to_string(),
+        }))
+    }
+
+    fn decode(encoding: Encoding, message: Message) -> Envelope {
+        match message {
+            Message::Text(text) => parse_envelope(&text).unwrap(),
+            Message::Binary(bytes) => decode_envelope(encoding, &bytes).unwrap(),
+            message => panic!("unexpected message {:?}", message),
+        }
+    }
+
+    #[test]
+    fn round_trips_in_every_encoding() {
+        let stream = Uuid::new_v4();
+        let envelope = Envelope { seq: Some(42), stream: Some(stream), ..candle(3.0, 0.5) };
+
+        for encoding in [Encoding::Json, Encoding::MessagePack, Encoding::Cbor] {
+            let message = envelope.encode(encoding).unwrap();
+            assert_eq!(matches!(message, Message::Text(_)), encoding == Encoding::Json);
+
+            let decoded = decode(encoding, message);
+
+            assert_eq!(decoded.version, PROTOCOL_VERSION);
+            assert_eq!(decoded.seq, Some(42));
+            assert_eq!(decoded.stream, Some(stream));
+            assert_eq!(serde_json::to_value(&decoded.frame).unwrap(), serde_json::to_value(&envelope.frame).unwrap());
+        }
+    }
+
+    #[test]
+    fn parses_a_resuming_subscription() {
+        let stream = Uuid::new_v4();
+        let text = format!(r#"{{"version":1,"type":"control","data":{{"action":"subscribe","symbol":"BTC","timerange":"1m","kind":"candle","last_seq":7,"stream":"{}"}}}}"#, stream);
+
+        match parse_envelope(&text).unwrap().frame {
+            Frame::Control(Control::Subscribe { topic, last_seq, stream: resumed }) => {
+                assert_eq!(topic, Topic { symbol: "BTC".to_string(), timerange: Some("1m".to_string()), kind: EntityKind::Candle });
+                assert_eq!(last_seq, Some(7));
+                assert_eq!(resumed, Some(stream));
+            },
+            frame => panic!("unexpected frame {:?}", frame),
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_frames_and_other_versions() {
+        assert_eq!(parse_envelope("{").unwrap_err().code, ErrorCode::MalformedFrame);
+        assert_eq!(decode_envelope(Encoding::Cbor, b"\xff").unwrap_err().code, ErrorCode::MalformedFrame);
+
+        let text = r#"{"version":2,"type":"heartbeat","data":{"timestamp":"2024-01-01T08:00:00Z"}}"#;
+        assert_eq!(parse_envelope(text).unwrap_err().code, ErrorCode::UnsupportedVersion);
+    }
+
+    #[test]
+    fn validates_publications() {
+        assert_eq!(validate_publication(&candle(3.0, 0.5)).unwrap().symbol, "BTC");
+
+        assert_eq!(validate_publication(&Envelope::heartbeat()).unwrap_err().code, ErrorCode::UnexpectedFrame);
+        assert_eq!(validate_publication(&candle(0.5, 3.0)).unwrap_err().code, ErrorCode::InvalidEntity);
+        assert_eq!(validate_publication(&candle(f64::NAN, 0.5)).unwrap_err().code, ErrorCode::InvalidEntity);
+    }
+}
@@ -0,0 +1,69 @@
+use crate::websocket::structures::{ClientQueue, Publisher, Publishers, PublisherState};
+
+use chrono::Utc;
+use std::sync::Arc;
+use uuid::Uuid;
+
+// Close codes sent to the senders
+pub const PUBLISHER_TAKEN_CLOSE_CODE: u16 = 4009;
+pub const PUBLISHER_REPLACED_CLOSE_CODE: u16 = 4010;
+
+// Take the slot of a publisher
+// With takeover, the current holder is closed and replaced
+// Returns false when the slot is already taken
+pub fn claim_publisher(publishers: &Publishers, name: &str, id: Uuid, queue: Arc<ClientQueue>, takeover: bool) -> bool {
+    let mut guard = publishers.lock().unwrap();
+
+    if let Some(current) = guard.get(name) {
+        if !takeover {
+            return false;
+        }
+
+        current.queue.close(PUBLISHER_REPLACED_CLOSE_CODE, "Replaced by a new publisher");
+    }
+
+    guard.insert(name.to_string(), Publisher {
+        id,
+        queue,
+        connected_at: Utc::now(),
+        messages: 0,
+    });
+
+    true
+}
+
+// Free the slot, unless it was taken over meanwhile
+pub fn release_publisher(publishers: &Publishers, name: &str, id: Uuid) {
+    let mut guard = publishers.lock().unwrap();
+
+    if guard.get(name).is_some_and(|publisher| publisher.id == id) {
+        guard.remove(name);
+    }
+}
+
+pub fn record_publication(publishers: &Publishers, name: &str, id: Uuid) {
+    let mut guard = publishers.lock().unwrap();
+
+    if let Some(publisher) = guard.get_mut(name).filter(|publisher| publisher.id == id) {
+        publisher.messages += 1;
+    }
+}
+
+// Close every sender once its queue is flushed
+pub fn close_publishers(publishers: &Publishers, code: u16, reason: &str) {
+    for publisher in publishers.lock().unwrap().values() {
+        publisher.queue.close_after_flush(code, reason);
+    }
+}
+
+pub fn publisher_states(publishers: &Publishers) -> Vec<PublisherState> {
+    publishers.lock().unwrap()
+        .iter()
+        .map(|(name, publisher)| PublisherState {
+            name: name.clone(),
+            id: publisher.id,
+            connected_at: publisher.connected_at,
+            messages: publisher.messages,
+        })
+        .collect()
+}
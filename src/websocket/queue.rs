@@ -0,0 +1,347 @@
+use crate::{
+    metrics::structures::Metrics,
+    websocket::structures::{ClientQueue, Encoding, Envelope, OverflowPolicy, QueuedMessage, QueueSettings, Topic, SLOW_CONSUMER_CLOSE_CODE}
+};
+
+use axum::extract::ws::{CloseFrame, Message};
+use common::utils::log::{
+    LogFile,
+    LogLevel
+};
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Mutex
+    }
+};
+use tokio::sync::Notify;
+
+// Control frames a client can have queued, on top of the entity frames
+// A client that never reads has them pile up, so it is disconnected past this
+pub const CONTROL_CAPACITY: usize = 64;
+
+impl ClientQueue {
+    pub fn new(settings: QueueSettings, encoding: Encoding) -> Self {
+        ClientQueue {
+            buffer: Mutex::new(VecDeque::with_capacity(settings.capacity)),
+            encoding,
+            capacity: settings.capacity,
+            policy: settings.policy,
+            dropped: AtomicU64::new(0),
+            closed: AtomicBool::new(false),
+            notify: Notify::new(),
+        }
+    }
+
+    // Queue a message for the client
+    // Returns false once the queue is closed so the caller can stop feeding it
+    // Control frames (pings, pongs, snapshot ends, resyncs, errors) have no topic
+    // They are never dropped by the policy but have their own capacity
+    pub fn push(&self, topic: Option<&Topic>, message: Message) -> bool {
+        if self.closed.load(Ordering::Acquire) {
+            return false;
+        }
+
+        let mut buffer = self.buffer.lock().unwrap();
+
+        if topic.is_none() {
+            // Only the latest pong matters, the client just checks we are alive
+            if let Message::Pong(_) = message {
+                let queued_pong = buffer.iter().position(|queued| matches!(queued.message, Message::Pong(_)));
+
+                if let Some(index) = queued_pong {
+                    buffer[index].message = message;
+
+                    return true;
+                }
+            }
+
+            let controls = buffer.iter().filter(|queued| queued.topic.is_none()).count();
+
+            if controls >= CONTROL_CAPACITY {
+                drop(buffer);
+                self.close(SLOW_CONSUMER_CLOSE_CODE, "Slow consumer");
+
+                return false;
+            }
+        } else if buffer.len() >= self.capacity {
+            match self.policy {
+                OverflowPolicy::DropOldest => {
+                    self.drop_oldest(&mut buffer);
+                },
+                OverflowPolicy::DropNewest => {
+                    self.record_dropped(1);
+
+                    return true;
+                },
+                OverflowPolicy::CoalesceByTopic => {
+                    // Only the latest value of a topic matters
+                    // Without a queued message of the same topic we fall back to dropping the oldest
+                    let same_topic = buffer.iter().position(|queued| queued.topic.as_ref() == topic);
+
+                    match same_topic {
+                        Some(index) => {
+                            buffer[index].message = message;
+                            self.record_dropped(1);
+
+                            return true;
+                        },
+                        None => {
+                            self.drop_oldest(&mut buffer);
+                        },
+                    }
+                },
+                OverflowPolicy::Disconnect(code) => {
+                    // The client can't keep up, the new message is lost as well
+                    self.record_dropped(1);
+
+                    drop(buffer);
+                    self.close(code, "Slow consumer");
+
+                    return false;
+                },
+            }
+        }
+
+        buffer.push_back(QueuedMessage {
+            topic: topic.cloned(),
+            message,
+        });
+
+        drop(buffer);
+        self.notify.notify_one();
+
+        true
+    }
+
+    // Queue a frame in the encoding of the client
+    pub fn push_envelope(&self, topic: Option<&Topic>, envelope: &Envelope) -> bool {
+        match envelope.encode(self.encoding) {
+            Ok(message) => self.push(topic, message),
+            Err(e) => {
+                LogFile::add_log(LogLevel::Error, &format!("Failed to encode frame as {:?}: {}", self.encoding, e)).ok();
+
+                !self.closed.load(Ordering::Acquire)
+            }
+        }
+    }
+
+    // Replace everything still queued by a close frame
+    // The socket is closed once it has been written
+    pub fn close(&self, code: u16, reason: &str) {
+        let mut buffer = self.buffer.lock().unwrap();
+
+        self.record_dropped(buffer.len() as u64);
+        self.closed.store(true, Ordering::Release);
+
+        buffer.clear();
+        buffer.push_back(QueuedMessage {
+            topic: None,
+            message: Message::Close(Some(CloseFrame {
+                code,
+                reason: reason.into(),
+            })),
+        });
+
+        drop(buffer);
+        self.notify.notify_one();
+    }
+
+    // Close once the messages already queued are written
+    // Used on shutdown so the clients don't miss the tail of the feed
+    pub fn close_after_flush(&self, code: u16, reason: &str) {
+        let mut buffer = self.buffer.lock().unwrap();
+
+        if self.closed.swap(true, Ordering::AcqRel) {
+            return;
+        }
+
+        buffer.push_back(QueuedMessage {
+            topic: None,
+            message: Message::Close(Some(CloseFrame {
+                code,
+                reason: reason.into(),
+            })),
+        });
+
+        drop(buffer);
+        self.notify.notify_one();
+    }
+
+    // Wait for the next message to write on the socket
+    // Returns None once the queue is closed and drained
+    pub async fn pop(&self) -> Option<Message> {
+        loop {
+            if let Some(queued) = self.buffer.lock().unwrap().pop_front() {
+                return Some(queued.message);
+            }
+
+            if self.closed.load(Ordering::Acquire) {
+                return None;
+            }
+
+            self.notify.notified().await;
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.buffer.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Acquire)
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    // Drop the oldest entity frame, the control frames are kept
+    fn drop_oldest(&self, buffer: &mut VecDeque<QueuedMessage>) {
+        let oldest = buffer.iter().position(|queued| queued.topic.is_some());
+
+        if let Some(index) = oldest {
+            buffer.remove(index);
+            self.record_dropped(1);
+        }
+    }
+
+    fn record_dropped(&self, count: u64) {
+        self.dropped.fetch_add(count, Ordering::Relaxed);
+        Metrics::global().websocket_dropped_messages.inc_by(count);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CONTROL_CAPACITY;
+    use crate::websocket::structures::{ClientQueue, Encoding, EntityKind, OverflowPolicy, QueueSettings, Topic, SLOW_CONSUMER_CLOSE_CODE};
+
+    use axum::extract::ws::Message;
+
+    fn queue(policy: OverflowPolicy) -> ClientQueue {
+        ClientQueue::new(QueueSettings { capacity: 3, policy }, Encoding::Json)
+    }
+
+    fn topic(symbol: &str) -> Topic {
+        Topic {
+            symbol: symbol.to_string(),
+            timerange: Some("1m".to_string()),
+            kind: EntityKind::Candle,
+        }
+    }
+
+    fn text(value: &str) -> Message {
+        Message::Text(value.into())
+    }
+
+    // What is queued, the close frames as close:<code>
+    fn queued(queue: &ClientQueue) -> Vec<String> {
+        queue.buffer.lock().unwrap().iter()
+            .map(|queued| match &queued.message {
+                Message::Text(text) => text.to_string(),
+                Message::Ping(_) => "ping".to_string(),
+                Message::Pong(payload) => format!("pong:{}", String::from_utf8_lossy(payload)),
+                Message::Close(frame) => format!("close:{}", frame.as_ref().map_or(0, |frame| frame.code)),
+                Message::Binary(_) => "binary".to_string(),
+            })
+            .collect()
+    }
+
+    fn fill(queue: &ClientQueue) {
+        let btc = topic("BTC");
+        let eth = topic("ETH");
+
+        assert!(queue.push(Some(&btc), text("btc1")));
+        assert!(queue.push(Some(&eth), text("eth1")));
+        assert!(queue.push(Some(&btc), text("btc2")));
+    }
+
+    #[test]
+    fn drop_oldest_makes_room_for_the_new_frame() {
+        let queue = queue(OverflowPolicy::DropOldest);
+        fill(&queue);
+
+        assert!(queue.push(Some(&topic("ETH")), text("eth2")));
+        assert_eq!(queued(&queue), ["eth1", "btc2", "eth2"]);
+        assert_eq!(queue.dropped(), 1);
+    }
+
+    #[test]
+    fn drop_newest_keeps_the_queued_frames() {
+        let queue = queue(OverflowPolicy::DropNewest);
+        fill(&queue);
+
+        assert!(queue.push(Some(&topic("ETH")), text("eth2")));
+        assert_eq!(queued(&queue), ["btc1", "eth1", "btc2"]);
+        assert_eq!(queue.dropped(), 1);
+    }
+
+    #[test]
+    fn coalesce_replaces_the_frame_of_the_same_topic() {
+        let queue = queue(OverflowPolicy::CoalesceByTopic);
+        fill(&queue);
+
+        assert!(queue.push(Some(&topic("ETH")), text("eth2")));
+        assert_eq!(queued(&queue), ["btc1", "eth2", "btc2"]);
+
+        // No queued frame for this topic, the oldest is dropped
+        assert!(queue.push(Some(&topic("SOL")), text("sol1")));
+        assert_eq!(queued(&queue), ["eth2", "btc2", "sol1"]);
+        assert_eq!(queue.dropped(), 2);
+    }
+
+    #[test]
+    fn disconnect_closes_with_the_given_code() {
+        let queue = queue(OverflowPolicy::Disconnect(4000));
+        fill(&queue);
+
+        assert!(!queue.push(Some(&topic("ETH")), text("eth2")));
+        assert_eq!(queued(&queue), ["close:4000"]);
+        assert_eq!(queue.dropped(), 4);
+
+        // Nothing is queued after the close frame
+        assert!(!queue.push(None, text("error")));
+        assert_eq!(queued(&queue), ["close:4000"]);
+    }
+
+    #[test]
+    fn control_frames_are_kept_under_load() {
+        let queue = queue(OverflowPolicy::DropOldest);
+        fill(&queue);
+
+        assert!(queue.push(None, text("snapshot_complete")));
+        assert!(queue.push(Some(&topic("ETH")), text("eth2")));
+        assert!(queue.push(Some(&topic("ETH")), text("eth3")));
+
+        assert_eq!(queued(&queue), ["btc2", "snapshot_complete", "eth2", "eth3"]);
+    }
+
+    #[test]
+    fn pongs_are_coalesced() {
+        let queue = queue(OverflowPolicy::DropOldest);
+
+        for payload in ["a", "b", "c"] {
+            assert!(queue.push(None, Message::Pong(payload.as_bytes().to_vec().into())));
+        }
+
+        assert_eq!(queued(&queue), ["pong:c"]);
+    }
+
+    #[test]
+    fn too_many_control_frames_disconnect_the_client() {
+        let queue = queue(OverflowPolicy::DropOldest);
+
+        for _ in 0..CONTROL_CAPACITY {
+            assert!(queue.push(None, text("error")));
+        }
+
+        assert!(!queue.push(None, text("error")));
+        assert_eq!(queued(&queue), [format!("close:{}", SLOW_CONSUMER_CLOSE_CODE)]);
+    }
+}
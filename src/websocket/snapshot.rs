@@ -0,0 +1,159 @@
+use crate::{
+    database::graphql::{
+        pagination::{CANDLE_PAGES, ONE_D_STRUCTURE_PAGES, SESSION_PAGES, TREND_PAGES, TWO_D_STRUCTURE_PAGES},
+        selection::{select_rows, Selection}
+    },
+    websocket::structures::{ClientQueue, Clients, Control, EntityKind, Envelope, Frame, SnapshotSettings, Topic}
+};
+
+use common::utils::log::{
+    LogFile,
+    LogLevel
+};
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+use std::{
+    collections::HashMap,
+    sync::Arc
+};
+use uuid::Uuid;
+
+// Load the latest rows of a topic, oldest first
+pub async fn load_snapshot(pool: Arc<PgPool>, topic: &Topic, settings: &SnapshotSettings) -> Result<Vec<Frame>, sqlx::Error> {
+    let limit = match topic.kind {
+        EntityKind::Candle => settings.candles,
+        EntityKind::Session => settings.sessions,
+        EntityKind::Trend => settings.trends,
+        EntityKind::OneDStructure | EntityKind::TwoDStructure => settings.structures,
+    };
+
+    if limit <= 0 {
+        return Ok(Vec::new());
+    }
+
+    let selection = Selection::latest(topic.symbol.clone(), topic.timerange.clone(), limit);
+
+    let frames = match topic.kind {
+        EntityKind::Candle => to_frames(select_rows(&pool, &CANDLE_PAGES, &selection).await?, Frame::Candle),
+        EntityKind::Session => to_frames(select_rows(&pool, &SESSION_PAGES, &selection).await?, Frame::Session),
+        EntityKind::Trend => to_frames(select_rows(&pool, &TREND_PAGES, &selection).await?, Frame::Trend),
+        EntityKind::OneDStructure => to_frames(select_rows(&pool, &ONE_D_STRUCTURE_PAGES, &selection).await?, Frame::OneDStructure),
+        EntityKind::TwoDStructure => to_frames(select_rows(&pool, &TWO_D_STRUCTURE_PAGES, &selection).await?, Frame::TwoDStructure),
+    };
+
+    Ok(frames)
+}
+
+// Send the snapshot of a new subscription then switch it to live
+// Messages broadcast meanwhile were held by the hub, so nothing is lost
+// A held message of a row already in the snapshot is newer, it replaces the row
+pub async fn stream_snapshot(clients: Clients, pool: Arc<PgPool>, settings: SnapshotSettings, client_id: Uuid, queue: Arc<ClientQueue>, topic: Topic) {
+    let frames = match load_snapshot(pool, &topic, &settings).await {
+        Ok(frames) => frames,
+        Err(e) => {
+            // The client still gets the live stream
+            LogFile::add_log(LogLevel::Error, &format!("Failed to load snapshot for {:?}: {}", topic, e)).ok();
+
+            Vec::new()
+        }
+    };
+
+    // Hold the hub while flushing
+    // So no live message can slip in before the held ones
+    let mut guard = clients.lock().unwrap();
+
+    let Some(held) = guard.activate(client_id, topic.clone()) else {
+        return;
+    };
+
+    let (snapshot, live) = merge_held(frames, held);
+
+    for envelope in snapshot {
+        queue.push_envelope(Some(&topic), &envelope);
+    }
+
+    queue.push_envelope(None, &Envelope::control(Control::SnapshotComplete(topic.clone())));
+
+    for envelope in live {
+        queue.push_envelope(Some(&topic), &envelope);
+    }
+}
+
+// Rows are matched on their natural key, the values may differ in precision once stored
+// Returns the snapshot with the updated rows and the held messages of new rows
+fn merge_held(frames: Vec<Frame>, held: Vec<Envelope>) -> (Vec<Envelope>, Vec<Envelope>) {
+    let mut snapshot: Vec<Envelope> = frames.into_iter()
+        .map(Envelope::new)
+        .collect();
+
+    let rows: HashMap<(String, DateTime<Utc>), usize> = snapshot.iter()
+        .enumerate()
+        .filter_map(|(index, envelope)| envelope.frame.row_key().map(|key| (key, index)))
+        .collect();
+
+    let mut live = Vec::new();
+
+    for envelope in held {
+        match envelope.frame.row_key().and_then(|key| rows.get(&key)) {
+            // Held messages are in order, so the row ends with its latest value
+            Some(&index) => snapshot[index] = envelope,
+            None => live.push(envelope),
+        }
+    }
+
+    (snapshot, live)
+}
+
+fn to_frames<T>(rows: Vec<T>, frame: fn(T) -> Frame) -> Vec<Frame> {
+    // Rows are selected newest first
+    rows.into_iter()
+        .rev()
+        .map(frame)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::merge_held;
+    use crate::{websocket::structures::{Envelope, Frame}, Candle};
+
+    use chrono::{TimeZone, Utc};
+
+    fn candle(minute: u32, close: f64) -> Frame {
+        Frame::Candle(Candle {
+            symbol: "BTC".to_string(),
+            timerange: "1m".to_string(),
+            timestamp: Utc.with_ymd_and_hms(2024, 1, 1, 8, minute, 0).unwrap(),
+            open: 1.0,
+            high: 3.0,
+            low: 0.5,
+            close,
+            volume: 10.0,
+            direction: "Bullish".to_string(),
+        })
+    }
+
+    fn closes(envelopes: &[Envelope]) -> Vec<(Option<u64>, f64)> {
+        envelopes.iter()
+            .map(|envelope| match &envelope.frame {
+                Frame::Candle(candle) => (envelope.seq, candle.close),
+                frame => panic!("unexpected frame {:?}", frame),
+            })
+            .collect()
+    }
+
+    fn held(seq: u64, frame: Frame) -> Envelope {
+        Envelope { seq: Some(seq), ..Envelope::new(frame) }
+    }
+
+    #[test]
+    fn held_updates_replace_the_snapshot_rows() {
+        let frames = vec![candle(0, 1.5), candle(1, 2.0)];
+        let held = vec![held(7, candle(1, 2.5)), held(8, candle(2, 1.0)), held(9, candle(1, 2.8))];
+
+        let (snapshot, live) = merge_held(frames, held);
+
+        assert_eq!(closes(&snapshot), [(None, 1.5), (Some(9), 2.8)]);
+        assert_eq!(closes(&live), [(Some(8), 1.0)]);
+    }
+}
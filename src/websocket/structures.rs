@@ -1,17 +1,389 @@
+use crate::{database::structures::RetryPolicy, utils::env::{env_millis, env_value}, Candle, OneDStructures, Session, Trend, TwoDStructures};
+use common::entities::{
+    candle::CandleInput,
+    session::SessionInput,
+    structures::{OneDStructuresInput, TwoDStructuresInput},
+    trend::TrendInput
+};
+
 use axum::extract::ws::Message;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashMap,
-    sync::{Arc, Mutex}
+    collections::{HashMap, HashSet, VecDeque},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicU64},
+        Mutex
+    },
+    str::FromStr,
+    time::Duration
 };
-use tokio::sync::mpsc::UnboundedSender;
+use sqlx::PgPool;
+use tokio::sync::{mpsc, Notify};
+use tokio_util::sync::CancellationToken;
 use uuid::Uuid;
 
-pub type Clients = Arc<Mutex<HashMap<Uuid, UnboundedSender<Message>>>>;
+pub type Clients = Arc<Mutex<Hub>>;
+pub type Publishers = Arc<Mutex<HashMap<String, Publisher>>>;
+
+// Everything the websocket handlers share
+#[derive(Clone)]
+pub struct WebsocketState {
+    pub clients: Clients,
+    pub publishers: Publishers,
+    pub settings: WebsocketSettings,
+    pub pool: Arc<PgPool>,
+    // Set when the sender frames are persisted
+    pub persister: Option<mpsc::Sender<Frame>>,
+    // Cancelled when the server stops
+    pub shutdown: CancellationToken,
+}
+
+// Connected receivers indexed by the topics they subscribed to
+pub struct Hub {
+    // Random id of this hub, the sequence numbers restart with every new hub
+    pub stream: Uuid,
+    pub clients: HashMap<Uuid, Arc<ClientQueue>>,
+    pub topics: HashMap<Topic, HashSet<Uuid>>,
+    // Live messages held back while the snapshot of a subscription is sent
+    pub pending: HashMap<(Uuid, Topic), Vec<Envelope>>,
+    // Recent messages of every topic, to replay them on reconnection
+    pub history: HashMap<Topic, TopicHistory>,
+    pub history_capacity: usize,
+}
+
+pub struct TopicHistory {
+    pub next_seq: u64,
+    pub messages: VecDeque<(u64, Envelope)>,
+}
+
+// Bounded buffer between the hub and a client socket
+// So a stalled client can't make the server grow without limit
+pub struct ClientQueue {
+    pub buffer: Mutex<VecDeque<QueuedMessage>>,
+    // Encoding negotiated by the client at connect time
+    pub encoding: Encoding,
+    pub capacity: usize,
+    pub policy: OverflowPolicy,
+    pub dropped: AtomicU64,
+    pub closed: AtomicBool,
+    pub notify: Notify,
+}
+
+pub struct QueuedMessage {
+    pub topic: Option<Topic>,
+    pub message: Message,
+}
+
+// What to do when a client queue is full
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    DropOldest,
+    DropNewest,
+    // Replace the queued message of the same topic with the new one
+    CoalesceByTopic,
+    // Close the connection with the given close code
+    Disconnect(u16),
+}
+
+// Close code of a client disconnected for being too slow, unless another one is given
+pub const SLOW_CONSUMER_CLOSE_CODE: u16 = 1013;
+
+// drop_oldest, drop_newest, coalesce_by_topic, disconnect or disconnect:<close code>
+impl FromStr for OverflowPolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.split_once(':') {
+            Some(("disconnect", code)) => code.parse()
+                .map(OverflowPolicy::Disconnect)
+                .map_err(|_| format!("Invalid close code {}", code)),
+            _ => match value {
+                "drop_oldest" => Ok(OverflowPolicy::DropOldest),
+                "drop_newest" => Ok(OverflowPolicy::DropNewest),
+                "coalesce_by_topic" => Ok(OverflowPolicy::CoalesceByTopic),
+                "disconnect" => Ok(OverflowPolicy::Disconnect(SLOW_CONSUMER_CLOSE_CODE)),
+                _ => Err(format!("Unknown overflow policy {}", value)),
+            },
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct QueueSettings {
+    pub capacity: usize,
+    pub policy: OverflowPolicy,
+}
+
+impl Default for QueueSettings {
+    fn default() -> Self {
+        QueueSettings {
+            capacity: 1024,
+            policy: OverflowPolicy::DropOldest,
+        }
+    }
+}
+
+// How many rows are replayed when a receiver subscribes
+// Zero disables the snapshot for this kind
+#[derive(Clone, Copy, Debug)]
+pub struct SnapshotSettings {
+    pub candles: i64,
+    pub sessions: i64,
+    pub trends: i64,
+    pub structures: i64,
+}
+
+impl Default for SnapshotSettings {
+    fn default() -> Self {
+        SnapshotSettings {
+            candles: 500,
+            sessions: 20,
+            trends: 20,
+            structures: 100,
+        }
+    }
+}
+
+// Write the sender frames to Postgres, opt-in
+// Frames are inserted once a batch is full or when the interval elapses
+#[derive(Clone, Copy, Debug)]
+pub struct PersistenceSettings {
+    pub enabled: bool,
+    pub batch_size: usize,
+    pub flush_interval: Duration,
+    pub retry: RetryPolicy,
+}
+
+impl Default for PersistenceSettings {
+    fn default() -> Self {
+        PersistenceSettings {
+            enabled: false,
+            batch_size: 500,
+            flush_interval: Duration::from_secs(1),
+            retry: RetryPolicy::default(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct WebsocketSettings {
+    pub queue: QueueSettings,
+    pub snapshot: SnapshotSettings,
+    // Number of messages kept per topic for resuming clients
+    pub history: usize,
+    pub persistence: PersistenceSettings,
+}
+
+impl Default for WebsocketSettings {
+    fn default() -> Self {
+        WebsocketSettings {
+            queue: QueueSettings::default(),
+            snapshot: SnapshotSettings::default(),
+            history: 1024,
+            persistence: PersistenceSettings::default(),
+        }
+    }
+}
 
-pub struct Role (pub ClientRole);
+impl WebsocketSettings {
+    // The defaults, overridden by the PARAGON_WS_* variables that are set
+    // The persisted frames are retried like the mutations, with the PARAGON_INSERT_* variables
+    pub fn from_env() -> Self {
+        let default = WebsocketSettings::default();
+
+        WebsocketSettings {
+            queue: QueueSettings {
+                capacity: env_value("PARAGON_WS_QUEUE_CAPACITY").unwrap_or(default.queue.capacity),
+                policy: env_value("PARAGON_WS_QUEUE_POLICY").unwrap_or(default.queue.policy),
+            },
+            snapshot: SnapshotSettings {
+                candles: env_value("PARAGON_WS_SNAPSHOT_CANDLES").unwrap_or(default.snapshot.candles),
+                sessions: env_value("PARAGON_WS_SNAPSHOT_SESSIONS").unwrap_or(default.snapshot.sessions),
+                trends: env_value("PARAGON_WS_SNAPSHOT_TRENDS").unwrap_or(default.snapshot.trends),
+                structures: env_value("PARAGON_WS_SNAPSHOT_STRUCTURES").unwrap_or(default.snapshot.structures),
+            },
+            history: env_value("PARAGON_WS_HISTORY").unwrap_or(default.history),
+            persistence: PersistenceSettings {
+                enabled: env_value("PARAGON_WS_PERSISTENCE").unwrap_or(default.persistence.enabled),
+                batch_size: env_value("PARAGON_WS_PERSISTENCE_BATCH_SIZE").unwrap_or(default.persistence.batch_size),
+                flush_interval: env_millis("PARAGON_WS_PERSISTENCE_FLUSH_MS").unwrap_or(default.persistence.flush_interval),
+                retry: RetryPolicy::from_env(),
+            },
+        }
+    }
+}
+
+// Sender frames waiting to be inserted
+#[derive(Default)]
+pub struct PendingBatch {
+    pub candles: Vec<CandleInput>,
+    pub sessions: Vec<SessionInput>,
+    pub trends: Vec<TrendInput>,
+    pub one_d_structures: Vec<OneDStructuresInput>,
+    pub two_d_structures: Vec<TwoDStructuresInput>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ClientStats {
+    pub id: Uuid,
+    pub queued: usize,
+    pub dropped: u64,
+}
+
+// A connected sender, one per publisher name
+pub struct Publisher {
+    pub id: Uuid,
+    pub queue: Arc<ClientQueue>,
+    pub connected_at: DateTime<Utc>,
+    pub messages: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PublisherState {
+    pub name: String,
+    pub id: Uuid,
+    pub connected_at: DateTime<Utc>,
+    pub messages: u64,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ConnectParams {
+    // Replace the publisher currently holding the slot, for failover
+    #[serde(default)]
+    pub takeover: bool,
+    // Used when no subprotocol is negotiated
+    #[serde(default)]
+    pub encoding: Option<Encoding>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Encoding {
+    #[default]
+    Json,
+    #[serde(rename = "msgpack")]
+    MessagePack,
+    Cbor,
+}
+
+// The publisher name is only set for senders
+pub struct Role (pub ClientRole, pub Option<String>);
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum ClientRole {
     Sender,
     Receiver
-}
\ No newline at end of file
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EntityKind {
+    Candle,
+    Session,
+    Trend,
+    OneDStructure,
+    TwoDStructure,
+}
+
+// A stream of one entity kind for a symbol
+// Sessions don't have a timerange
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Topic {
+    pub symbol: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timerange: Option<String>,
+    pub kind: EntityKind,
+}
+
+// Every frame exchanged on the websocket, in both directions
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Envelope {
+    pub version: u32,
+    // Assigned by the hub, monotonic per topic
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
+    // The hub that assigned seq, a client resumes from it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stream: Option<Uuid>,
+    #[serde(flatten)]
+    pub frame: Frame,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", content = "data", rename_all = "snake_case")]
+pub enum Frame {
+    Candle(Candle),
+    Session(Session),
+    Trend(Trend),
+    OneDStructure(OneDStructures),
+    TwoDStructure(TwoDStructures),
+    Heartbeat {
+        timestamp: DateTime<Utc>,
+    },
+    Error {
+        code: ErrorCode,
+        message: String,
+    },
+    Control(Control),
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum Control {
+    // Sent by a receiver to choose what it gets
+    Subscribe {
+        #[serde(flatten)]
+        topic: Topic,
+        // Last sequence number received before a disconnection
+        #[serde(default)]
+        last_seq: Option<u64>,
+        // And the stream it belongs to
+        #[serde(default)]
+        stream: Option<Uuid>,
+    },
+    Unsubscribe(Topic),
+    // Sent by the server, everything after this frame is live
+    SnapshotComplete(Topic),
+    // Sent by the server when the missed messages are no longer retained
+    // The client gets a fresh snapshot instead
+    Resync(Topic),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    MalformedFrame,
+    UnsupportedVersion,
+    UnexpectedFrame,
+    InvalidEntity,
+}
+
+// Why a frame was rejected, sent back as an error frame
+#[derive(Clone, Debug)]
+pub struct ProtocolError {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{OverflowPolicy, SLOW_CONSUMER_CLOSE_CODE};
+
+    #[test]
+    fn parses_the_overflow_policies() {
+        assert_eq!("drop_oldest".parse(), Ok(OverflowPolicy::DropOldest));
+        assert_eq!("drop_newest".parse(), Ok(OverflowPolicy::DropNewest));
+        assert_eq!("coalesce_by_topic".parse(), Ok(OverflowPolicy::CoalesceByTopic));
+        assert_eq!("disconnect".parse(), Ok(OverflowPolicy::Disconnect(SLOW_CONSUMER_CLOSE_CODE)));
+        assert_eq!("disconnect:4000".parse(), Ok(OverflowPolicy::Disconnect(4000)));
+    }
+
+    #[test]
+    fn rejects_invalid_overflow_policies() {
+        for value in ["", "drop", "DropOldest", "disconnect:", "disconnect:abc", "disconnect:70000", "drop_oldest:1"] {
+            assert!(value.parse::<OverflowPolicy>().is_err(), "{} was accepted", value);
+        }
+    }
+}
@@ -1,6 +1,23 @@
-use crate::websocket::{
-    handler::websocket_handler,
-    structures::Clients
+use crate::{
+    health::{
+        health::{healthz, readyz, status},
+        structures::{FeedState, HealthState}
+    },
+    metrics::{
+        metrics::metrics_handler,
+        structures::Metrics
+    },
+    supervisor::structures::Health,
+    utils::{
+        shutdown::{GOING_AWAY_CLOSE_CODE, SHUTDOWN_TIMEOUT},
+        telemetry::http_trace_layer
+    },
+    websocket::{
+        handler::{clients_handler, publishers_handler, websocket_handler},
+        persistence::run_persister,
+        publishers::close_publishers,
+        structures::{Clients, Hub, Publishers, WebsocketSettings, WebsocketState}
+    }
 };
 
 use axum::{
@@ -12,18 +29,74 @@ use common::utils::log::{
     LogFile,
     LogLevel
 };
+use sqlx::postgres::PgPoolOptions;
 use std::{
     collections::HashMap,
     sync::{Arc, Mutex}
 };
-use tokio::net::TcpListener;
+use tokio::{
+    net::TcpListener,
+    sync::mpsc,
+    time::{sleep, timeout, Duration},
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{info_span, Instrument};
+
+pub async fn launch_websocket_server(address: String, database_url: String, settings: WebsocketSettings, subsystems: Health, shutdown: CancellationToken) -> Result<(), Box<dyn std::error::Error>> {
+    // Loads the snapshots and writes the persisted frames
+    // It connects lazily so the feed still works while Postgres is down
+    let pool = Arc::new(PgPoolOptions::new()
+        .max_connections(5)
+        .connect_lazy(&database_url)?);
+
+    // Stopped once the clients are gone, so their last frames are written
+    let persister_stop = CancellationToken::new();
+
+    let (persister, persister_task) = if settings.persistence.enabled {
+        // One batch can be waiting while the previous one is written
+        let (sender, receiver) = mpsc::channel(settings.persistence.batch_size.max(1));
+        let task = tokio::spawn(run_persister(Arc::clone(&pool), settings.persistence, receiver, persister_stop.clone())
+            .instrument(info_span!("websocket.persister")));
+
+        (Some(sender), Some(task))
+    } else {
+        (None, None)
+    };
+
+    let clients: Clients = Arc::new(Mutex::new(Hub::new(settings.history)));
+    let publishers: Publishers = Arc::new(Mutex::new(HashMap::new()));
 
-pub async fn launch_websocket_server(address: String) -> Result<(), Box<dyn std::error::Error>> {
-    let clients: Clients = Arc::new(Mutex::new(HashMap::new()));
+    let state = WebsocketState {
+        clients: Arc::clone(&clients),
+        publishers: Arc::clone(&publishers),
+        settings,
+        pool: Arc::clone(&pool),
+        persister,
+        shutdown: shutdown.clone(),
+    };
+
+    Metrics::global().watch_pool("websocket", pool.as_ref().clone());
+
+    let health = HealthState {
+        pool: pool.as_ref().clone(),
+        subsystems,
+        feed: Some(FeedState {
+            clients: Arc::clone(&clients),
+            publishers: Arc::clone(&publishers),
+        }),
+    };
 
     let app = Router::new()
         .route("/ws", get(websocket_handler))
-        .layer(Extension(clients));
+        .route("/ws/clients", get(clients_handler)) // Per client queue statistics
+        .route("/ws/publishers", get(publishers_handler)) // Connected publishers
+        .route("/healthz", get(healthz)) // Liveness
+        .route("/readyz", get(readyz)) // Readiness
+        .route("/status", get(status)) // Detailed status
+        .route("/metrics", get(metrics_handler)) // Prometheus metrics
+        .layer(Extension(state))
+        .layer(Extension(health))
+        .layer(http_trace_layer());
 
     let listener = TcpListener::bind(&address).await;
 
@@ -35,13 +108,79 @@ pub async fn launch_websocket_server(address: String) -> Result<(), Box<dyn std:
 
     let listener = listener.unwrap();
 
-    if let Err(e) = axum::serve(listener, app).await {
+    // Stop accepting connections on shutdown
+    // And close every client once what is already queued has been written
+    let server = axum::serve(listener, app)
+        .with_graceful_shutdown({
+            let shutdown = shutdown.clone();
+            let clients = Arc::clone(&clients);
+            let publishers = Arc::clone(&publishers);
+
+            async move {
+                shutdown.cancelled().await;
+
+                clients.lock().unwrap().close_all(GOING_AWAY_CLOSE_CODE, "Server shutting down");
+                close_publishers(&publishers, GOING_AWAY_CLOSE_CODE, "Server shutting down");
+            }
+        })
+        .into_future();
+
+    let drain = async {
+        server.await?;
+
+        // Upgraded sockets outlive the server, wait for them to be flushed
+        wait_for_disconnections(&clients, &publishers).await;
+
+        Ok::<(), std::io::Error>(())
+    };
+
+    let drain_deadline = async {
+        shutdown.cancelled().await;
+        sleep(SHUTDOWN_TIMEOUT).await;
+    };
+
+    let result = tokio::select! {
+        result = drain => result,
+        _ = drain_deadline => {
+            LogFile::add_log(LogLevel::Error, "WebSocket clients didn't drain in time, closing anyway").ok();
+
+            Ok(())
+        },
+    };
+
+    // Write the frames still waiting for the database
+    persister_stop.cancel();
+
+    let persisted = match persister_task {
+        Some(task) => timeout(SHUTDOWN_TIMEOUT, task).await.is_ok(),
+        None => true,
+    };
+
+    if !persisted {
+        LogFile::add_log(LogLevel::Error, "Pending websocket frames weren't persisted in time").ok();
+    }
+
+    pool.close().await;
+
+    if let Err(e) = result {
         LogFile::add_log(LogLevel::Error, &format!("Failed to start server: {}", e)).ok();
 
         return Err(Box::new(e));
     }
 
-    LogFile::add_log(LogLevel::Info, &format!("WebSocket server running on {}", address)).ok();
+    LogFile::add_log(LogLevel::Info, &format!("WebSocket server on {} stopped", address)).ok();
 
     Ok(())
+}
+
+async fn wait_for_disconnections(clients: &Clients, publishers: &Publishers) {
+    loop {
+        let connected = !clients.lock().unwrap().clients.is_empty() || !publishers.lock().unwrap().is_empty();
+
+        if !connected {
+            return;
+        }
+
+        sleep(Duration::from_millis(50)).await;
+    }
 }
\ No newline at end of file